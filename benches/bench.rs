@@ -1,6 +1,6 @@
 use adaptive_algorithms::adaptive_bench::*;
 use criterion::*;
-use mergesort::mergesort;
+use mergesort::{mergesort, par_merge, par_merge_keys, par_merge_with_branches, MergesortConfig};
 use rayon::prelude::*;
 use rayon_adaptive::adaptive_sort;
 extern crate num;
@@ -92,6 +92,36 @@ impl<'a, T: Clone> MergeSort<'a, T> {
         }
     }
 }
+// No scratch buffer, but about a quarter more comparisons and O(n log^2 n) moves in the rotations
+// instead of O(n log n), compare with "Adaptive Mergesort"
+struct InPlace<'a, T> {
+    original: &'a Vec<T>,
+    data: Vec<T>,
+}
+
+impl<'a, T: Send + Sync + Copy + Ord> Benchable<'a, T> for InPlace<'a, T> {
+    fn start(&mut self) -> Option<T> {
+        *self = InPlace::new(self.original);
+        rayon::join(|| {}, || {});
+        MergesortConfig::new()
+            .in_place(true)
+            .sort(&mut self.data)
+            .unwrap();
+        // assert!(self.data.windows(2).all(|w| w[0] <= w[1]));
+        None
+    }
+    fn name(&self) -> &'static str {
+        "Adaptive Mergesort in place"
+    }
+}
+impl<'a, T: Clone> InPlace<'a, T> {
+    fn new(data: &'a Vec<T>) -> Self {
+        InPlace {
+            original: data,
+            data: data.clone(),
+        }
+    }
+}
 struct RayonAdaptive<'a, T> {
     original: &'a Vec<T>,
     data: Vec<T>,
@@ -204,6 +234,9 @@ fn bench(c: &mut Criterion) {
                 let test = MergeSort::new(&v);
                 let x = TestConfig::new(v.len(), *i, Some(s), test);
                 tests.push(x);
+                let test = InPlace::new(&v);
+                let x = TestConfig::new(v.len(), *i, Some(s), test);
+                tests.push(x);
             }
             let test = RayonAdaptive::new(&v);
             let x = TestConfig::new(v.len(), *i, None, test);
@@ -220,5 +253,48 @@ fn bench(c: &mut Criterion) {
 
     // group.finish();
 }
-criterion_group!(benches, bench);
+fn kernels(c: &mut Criterion) {
+    let mut group = c.benchmark_group("Merge kernels");
+    group.warm_up_time(std::time::Duration::new(1, 0));
+    group.measurement_time(std::time::Duration::new(1, 0));
+    group.sample_size(10);
+    let len = 1 << 20;
+    let random = || -> Vec<u64> { std::iter::repeat_with(rand::random).take(len / 2).collect() };
+    // (left, right), they get sorted below
+    let inputs: Vec<(&str, Vec<u64>, Vec<u64>)> = vec![
+        ("random", random(), random()),
+        // every comparison goes the other way than the one before
+        (
+            "interleaved",
+            (0..len as u64 / 2).map(|x| 2 * x).collect(),
+            (0..len as u64 / 2).map(|x| 2 * x + 1).collect(),
+        ),
+        (
+            "few unique",
+            random().iter().map(|x| x % 4).collect(),
+            random().iter().map(|x| x % 4).collect(),
+        ),
+    ];
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(1)
+        .build()
+        .unwrap();
+    for (name, mut left, mut right) in inputs {
+        left.sort();
+        right.sort();
+        let mut out = vec![0; len];
+        group.bench_function(format!("branchless, {}", name), |b| {
+            b.iter(|| pool.install(|| par_merge(&left, &right, &mut out)))
+        });
+        group.bench_function(format!("branches, {}", name), |b| {
+            b.iter(|| pool.install(|| par_merge_with_branches(&left, &right, &mut out)))
+        });
+        group.bench_function(format!("simd, {}", name), |b| {
+            b.iter(|| pool.install(|| par_merge_keys(&left, &right, &mut out)))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench, kernels);
 criterion_main!(benches);
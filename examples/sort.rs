@@ -1,4 +1,3 @@
-use adaptive_algorithms::*;
 use mergesort::*;
 use rand::prelude::*;
 pub fn main() -> Result<(), Box<dyn std::error::Error>> {
@@ -13,11 +12,8 @@ pub fn main() -> Result<(), Box<dyn std::error::Error>> {
     let checksum: u64 = v.iter().cloned().sum();
     println!("Finished generating");
 
-    let pool = rayon::get_thread_pool();
-    pool.install(|| {
-        // rayon::join(|| {}, || {});
-        mergesort(&mut v);
-    });
+    let mut sorter = Sorter::new();
+    sorter.sort(&mut v);
     assert_eq!(checksum, v.iter().sum::<u64>(), "failed merging");
     assert!(v.windows(2).all(|w| w[0] <= w[1]));
     #[cfg(feature = "statistics")]
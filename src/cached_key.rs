@@ -0,0 +1,93 @@
+use adaptive_algorithms::Task;
+use std::ptr;
+
+// number of keys computed per step
+const WORK_SIZE: usize = 81;
+
+/// Computes `(f(data[i]), i)` for every element and writes it into (uninitialized) `keys`.
+pub struct ComputeKeys<'a, T, K, F>
+where
+    F: Fn(&T) -> K,
+{
+    pub data: &'a [T],
+    pub keys: *mut (K, usize),
+    pub offset: usize, // index of data[0] in the whole input
+    pub f: &'a F,
+}
+unsafe impl<'a, T, K, F> Send for ComputeKeys<'a, T, K, F>
+where
+    T: Sync,
+    K: Send,
+    F: Fn(&T) -> K + Sync,
+{
+}
+
+impl<'a, T, K, F> ComputeKeys<'a, T, K, F>
+where
+    F: Fn(&T) -> K,
+{
+    /// `keys` needs to have space for at least `data.len()` elements
+    pub fn new(data: &'a [T], keys: *mut (K, usize), f: &'a F) -> Self {
+        ComputeKeys {
+            data,
+            keys,
+            offset: 0,
+            f,
+        }
+    }
+}
+
+impl<'a, T, K, F> Task for ComputeKeys<'a, T, K, F>
+where
+    T: Sync,
+    K: Send,
+    F: Fn(&T) -> K + Sync,
+{
+    fn step(&mut self) {
+        let work_size = std::cmp::min(WORK_SIZE, self.data.len());
+        let (piece, rest) = self.data.split_at(work_size);
+        for (i, x) in piece.iter().enumerate() {
+            unsafe {
+                ptr::write(self.keys.add(i), ((self.f)(x), self.offset + i));
+            }
+        }
+        self.data = rest;
+        self.keys = unsafe { self.keys.add(work_size) };
+        self.offset += work_size;
+    }
+    fn is_finished(&self) -> bool {
+        self.data.is_empty()
+    }
+    fn split(&mut self, mut runner: impl FnMut(&mut Vec<&mut Self>), _steal_counter: usize) {
+        let mid = self.data.len() / 2;
+        let (left, right) = self.data.split_at(mid);
+        let mut other = ComputeKeys {
+            data: right,
+            keys: unsafe { self.keys.add(mid) },
+            offset: self.offset + mid,
+            f: self.f,
+        };
+        self.data = left;
+        runner(&mut vec![self, &mut other]);
+    }
+    fn can_split(&self) -> bool {
+        return self.data.len() > WORK_SIZE * 32;
+    }
+    fn work(&self) -> Option<(&'static str, usize)> {
+        Some(("Computing keys", self.data.len()))
+    }
+}
+
+/// Reorders `data` so that `data[i]` becomes the element that was at `indices[i].1`.
+/// `indices` is used as scratch space. Same algorithm as `slice::sort_by_cached_key`.
+pub fn permute<T, K>(data: &mut [T], indices: &mut [(K, usize)]) {
+    for i in 0..data.len() {
+        let mut index = indices[i].1;
+        // the element at `index` was already swapped away, follow it
+        while index < i {
+            index = indices[index].1;
+        }
+        indices[i].1 = index;
+        data.swap(i, index);
+    }
+}
@@ -0,0 +1,99 @@
+use std::cmp::Ordering;
+use std::sync::atomic::{AtomicBool, Ordering as AtomicOrdering};
+use std::sync::Mutex;
+
+/// What the merge tasks use to compare elements.
+/// Every `Fn(&T, &T) -> Ordering` closure is a comparator that never aborts.
+pub trait Comparator<T> {
+    fn compare(&self, a: &T, b: &T) -> Ordering;
+    /// Once this returns true the tasks stop at their next step. The result is not sorted then,
+    /// but the data still holds every element exactly once.
+    fn aborted(&self) -> bool {
+        false
+    }
+    /// Sorts a leaf block.
+    fn sort_leaf(&self, piece: &mut [T]) {
+        piece.sort_by(|a, b| self.compare(a, b))
+    }
+    /// Merges whole blocks from the fronts of the inputs (two or three `(start, end)` ranges)
+    /// into `output` with a faster kernel, and moves the pointers past what it merged. It leaves
+    /// at least one element on every input, the merge tasks do the rest one by one.
+    /// By default there is no such kernel (see `simd`).
+    ///
+    /// # Safety
+    /// Every range has to be readable, and the output writable for all of them together.
+    unsafe fn merge_blocks(&self, _inputs: &mut [(*const T, *const T)], _output: &mut *mut T) {}
+}
+
+impl<T, F> Comparator<T> for F
+where
+    F: Fn(&T, &T) -> Ordering,
+{
+    fn compare(&self, a: &T, b: &T) -> Ordering {
+        self(a, b)
+    }
+}
+
+/// Wraps a fallible comparison function. The first error is kept and aborts the sort.
+pub struct TryCompare<F, E> {
+    compare: F,
+    error: Mutex<Option<E>>,
+    failed: AtomicBool,
+}
+
+impl<F, E> TryCompare<F, E> {
+    pub fn new(compare: F) -> Self {
+        TryCompare {
+            compare,
+            error: Mutex::new(None),
+            failed: AtomicBool::new(false),
+        }
+    }
+    /// Returns the first error that happened, if any.
+    pub fn into_result(self) -> Result<(), E> {
+        match self.error.into_inner().unwrap() {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+}
+
+impl<T, F, E> Comparator<T> for TryCompare<F, E>
+where
+    F: Fn(&T, &T) -> Result<Ordering, E>,
+{
+    fn compare(&self, a: &T, b: &T) -> Ordering {
+        if self.aborted() {
+            // don't bother anymore, we just need to finish up quickly
+            return Ordering::Equal;
+        }
+        match (self.compare)(a, b) {
+            Ok(ordering) => ordering,
+            Err(e) => {
+                let mut error = self.error.lock().unwrap();
+                if error.is_none() {
+                    *error = Some(e);
+                }
+                self.failed.store(true, AtomicOrdering::Relaxed);
+                Ordering::Equal
+            }
+        }
+    }
+    fn aborted(&self) -> bool {
+        self.failed.load(AtomicOrdering::Relaxed)
+    }
+    fn sort_leaf(&self, piece: &mut [T]) {
+        // Once we abort, the comparisons aren't consistent anymore and slice::sort_by may panic.
+        // A binary insertion sort doesn't care, it just leaves the piece in some order.
+        for i in 1..piece.len() {
+            if self.aborted() {
+                return;
+            }
+            let (sorted, rest) = piece.split_at(i);
+            // insert after all equal elements to stay stable
+            let position =
+                sorted.partition_point(|x| self.compare(x, &rest[0]) != Ordering::Greater);
+            piece[position..=i].rotate_right(1);
+        }
+    }
+}
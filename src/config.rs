@@ -0,0 +1,168 @@
+use crate::compare::Comparator;
+use std::cmp::Ordering;
+use std::fmt;
+
+/// Tuning knobs of the parallel mergesort.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MergesortConfig {
+    pub(crate) blocksize: usize,
+    pub(crate) merge_work_size: usize,
+    pub(crate) split_factor: usize,
+    pub(crate) limit: usize, // only the first `limit` outputs need to be sorted
+    pub(crate) natural: bool,
+    pub(crate) arity: usize,
+    pub(crate) half_buffer: bool,
+    pub(crate) in_place: bool,
+}
+
+impl Default for MergesortConfig {
+    fn default() -> Self {
+        MergesortConfig {
+            blocksize: 81,
+            merge_work_size: 81,
+            split_factor: 32,
+            limit: usize::MAX,
+            natural: false,
+            arity: 3,
+            half_buffer: false,
+            in_place: false,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ConfigError {
+    /// The leaf block size needs to be at least 1.
+    ZeroBlocksize,
+    /// The merge step size needs to be at least 1.
+    ZeroMergeWorkSize,
+    /// A sorting task gives away blocks of at least `3 * blocksize` elements when it splits, so
+    /// it needs to hold more than that to be able to split.
+    SplitFactorTooSmall(usize),
+    /// Merges can only take 2, 3 or 4 pieces at once.
+    UnsupportedArity(usize),
+    /// `blocksize * split_factor` or `merge_work_size * split_factor` doesn't fit in a usize.
+    Overflow,
+    /// The in-place mode doesn't go together with the named one (`half_buffer` or `natural`).
+    InPlaceWith(&'static str),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::ZeroBlocksize => write!(f, "the block size must not be 0"),
+            ConfigError::ZeroMergeWorkSize => write!(f, "the merge work size must not be 0"),
+            ConfigError::SplitFactorTooSmall(factor) => write!(
+                f,
+                "the split factor must be at least 3, but it is {}",
+                factor
+            ),
+            ConfigError::UnsupportedArity(arity) => {
+                write!(f, "the merge arity must be 2, 3 or 4, but it is {}", arity)
+            }
+            ConfigError::Overflow => write!(f, "the split thresholds overflow"),
+            ConfigError::InPlaceWith(mode) => {
+                write!(f, "the in-place mode can't be combined with {}", mode)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl MergesortConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Number of elements each leaf sorts sequentially (default 81).
+    pub fn blocksize(mut self, blocksize: usize) -> Self {
+        self.blocksize = blocksize;
+        self
+    }
+    /// Number of elements a merge task takes from each input per step (default 81).
+    pub fn merge_work_size(mut self, merge_work_size: usize) -> Self {
+        self.merge_work_size = merge_work_size;
+        self
+    }
+    /// Tasks only split if they have more than `split_factor` steps of work left (default 32).
+    pub fn split_factor(mut self, split_factor: usize) -> Self {
+        self.split_factor = split_factor;
+        self
+    }
+    /// Sorts runs that are already in the input instead of fixed blocks (default false).
+    /// Descending runs get reversed, runs shorter than a block get extended and sorted.
+    pub fn natural(mut self, natural: bool) -> Self {
+        self.natural = natural;
+        self
+    }
+    /// How many pieces a merge takes at once: 2, 3 or 4 (default 3).
+    pub fn arity(mut self, arity: usize) -> Self {
+        self.arity = arity;
+        self
+    }
+    /// Sorts with a scratch buffer of only half the input size (default false). The two halves get
+    /// sorted one after the other, then merged by moving the left one into the buffer.
+    pub fn half_buffer(mut self, half_buffer: bool) -> Self {
+        self.half_buffer = half_buffer;
+        self
+    }
+    /// Sorts without any scratch buffer (default false), at the cost of more moves. Merges are
+    /// always two-way then. It can't be combined with `half_buffer` or `natural`.
+    pub fn in_place(mut self, in_place: bool) -> Self {
+        self.in_place = in_place;
+        self
+    }
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.blocksize == 0 {
+            return Err(ConfigError::ZeroBlocksize);
+        }
+        if self.merge_work_size == 0 {
+            return Err(ConfigError::ZeroMergeWorkSize);
+        }
+        if self.split_factor < 3 {
+            return Err(ConfigError::SplitFactorTooSmall(self.split_factor));
+        }
+        if !(2..=4).contains(&self.arity) {
+            return Err(ConfigError::UnsupportedArity(self.arity));
+        }
+        if self.blocksize.checked_mul(self.split_factor).is_none()
+            || self
+                .merge_work_size
+                .checked_mul(self.split_factor)
+                .is_none()
+        {
+            return Err(ConfigError::Overflow);
+        }
+        if self.in_place && self.half_buffer {
+            return Err(ConfigError::InPlaceWith("half_buffer"));
+        }
+        if self.in_place && self.natural {
+            return Err(ConfigError::InPlaceWith("natural"));
+        }
+        Ok(())
+    }
+    /// Sorts `data` with this configuration, see `mergesort`.
+    pub fn sort<T>(&self, data: &mut [T]) -> Result<(), ConfigError>
+    where
+        T: Ord + Send,
+    {
+        self.sort_by(data, |a, b| a.cmp(b))
+    }
+    /// Sorts `data` with this configuration, see `mergesort_by`.
+    pub fn sort_by<T, F>(&self, data: &mut [T], compare: F) -> Result<(), ConfigError>
+    where
+        T: Send,
+        F: Fn(&T, &T) -> Ordering + Sync,
+    {
+        self.sort_with(data, &compare)
+    }
+    pub(crate) fn sort_with<T, F>(&self, data: &mut [T], compare: &F) -> Result<(), ConfigError>
+    where
+        T: Send,
+        F: Comparator<T> + Sync,
+    {
+        self.validate()?;
+        crate::mergesort_with(data, compare, self);
+        Ok(())
+    }
+}
@@ -0,0 +1,328 @@
+//! Sorting without a scratch buffer. The pieces get merged with SymMerge (Kim and Kutzner), which
+//! only rotates elements around. That still takes O(n log n) comparisons for the whole sort, but
+//! O(n log^2 n) moves instead of O(n log n). The leaves get sorted with `sort_leaf`, so that needs
+//! memory for a block, but nothing grows with the input.
+use crate::compare::Comparator;
+use crate::merge::fuse_slices;
+use crate::{cut_off_left, cut_off_right, policy, MergesortConfig};
+use adaptive_algorithms::Task;
+use std::cmp::Ordering;
+
+/// Sorts `data` in place. Merges are always two-way, whatever the configured arity.
+pub(crate) fn sort_in_place<T, F>(data: &mut [T], compare: &F, config: &MergesortConfig)
+where
+    T: Send,
+    F: Comparator<T> + Sync,
+{
+    let len = data.len();
+    let mut sort = InPlaceSort {
+        data,
+        pieces: Vec::new(),
+        powers: Vec::new(),
+        first_run: None,
+        last_run: None,
+        offset: 0,
+        len,
+        compare,
+        config: *config,
+        merging: false,
+    };
+    sort.run();
+    sort.merge_down(0);
+    debug_assert!(
+        compare.aborted()
+            || sort.pieces[0]
+                .windows(2)
+                .all(|w| compare.compare(&w[0], &w[1]) != Ordering::Greater)
+    );
+}
+
+/// Like `Mergesort`, but the pieces are just sorted slices of the data.
+struct InPlaceSort<'a, T, F>
+where
+    T: Send,
+    F: Comparator<T> + Sync,
+{
+    data: &'a mut [T], // what's left to sort
+    pieces: Vec<&'a mut [T]>,
+    powers: Vec<u32>, // the boundaries between the pieces, see policy
+    first_run: Option<(usize, usize)>,
+    last_run: Option<(usize, usize)>,
+    offset: usize,
+    len: usize,
+    compare: &'a F,
+    config: MergesortConfig,
+    merging: bool,
+}
+
+impl<'a, T, F> InPlaceSort<'a, T, F>
+where
+    T: Send,
+    F: Comparator<T> + Sync,
+{
+    /// Puts a sorted run on the stack, after merging whatever the policy wants merged first.
+    fn push(&mut self, piece: &'a mut [T], run: (usize, usize)) {
+        match self.last_run {
+            Some(last) => {
+                let next = policy::power(self.len, 2, last, run);
+                self.merge_down(next);
+                self.powers.push(next);
+            }
+            None => self.first_run = Some(run),
+        }
+        self.pieces.push(piece);
+        self.last_run = Some(run);
+    }
+    /// Merges pieces until the boundary with power `next` can go on the stack.
+    fn merge_down(&mut self, next: u32) {
+        while !self.compare.aborted() && policy::next_merge(&self.powers, next, 2).is_some() {
+            self.powers.pop();
+            let right = self.pieces.pop().unwrap();
+            let left = self.pieces.pop().unwrap();
+            let mid = left.len();
+            let piece = fuse_slices(left, right);
+            // the merge only keeps a pointer, so we can hand ourselves over to be split meanwhile
+            let mut merge = SymMerge::new(
+                piece,
+                mid,
+                self.config.merge_work_size,
+                self.config.split_factor,
+                self.compare,
+            );
+            self.pieces.push(piece);
+            self.merging = true;
+            merge.run_with(self);
+            self.merging = false;
+        }
+    }
+}
+
+impl<'a, T, F> Task for InPlaceSort<'a, T, F>
+where
+    T: Send,
+    F: Comparator<T> + Sync,
+{
+    fn step(&mut self) {
+        if self.compare.aborted() {
+            // leave the rest of the data where it is, unsorted
+            self.data = &mut [];
+            return;
+        }
+        let work_size = std::cmp::min(self.config.blocksize, self.data.len());
+        let piece = cut_off_left(&mut self.data, work_size);
+        self.compare.sort_leaf(piece);
+        let run = (self.offset, self.offset + work_size);
+        self.offset += work_size;
+        self.push(piece, run);
+    }
+    fn is_finished(&self) -> bool {
+        self.data.is_empty()
+    }
+    fn split(&mut self, mut runner: impl FnMut(&mut Vec<&mut Self>), _steal_counter: usize) {
+        // at the same boundaries as Mergesort
+        let blocksize = self.config.blocksize;
+        let from = self.offset / blocksize;
+        let to = (self.offset + self.data.len()).div_ceil(blocksize);
+        let split = policy::split_block(self.len, 2, blocksize, from, to) * blocksize - self.offset;
+        let right_data = cut_off_right(&mut self.data, split);
+        let mut other = InPlaceSort {
+            offset: self.offset + self.data.len(),
+            data: right_data,
+            pieces: Vec::new(),
+            powers: Vec::new(),
+            first_run: None,
+            last_run: None,
+            len: self.len,
+            compare: self.compare,
+            config: self.config,
+            merging: false,
+        };
+        runner(&mut vec![self, &mut other]);
+    }
+    fn can_split(&self) -> bool {
+        // see Mergesort
+        !self.merging && self.data.len() > self.config.blocksize * self.config.split_factor
+    }
+    fn fuse(&mut self, other: &mut Self) {
+        if self.compare.aborted() || other.last_run.is_none() {
+            self.pieces.append(&mut other.pieces);
+            return;
+        }
+        let first = policy::power(
+            self.len,
+            2,
+            self.last_run.unwrap(),
+            other.first_run.unwrap(),
+        );
+        let powers = std::iter::once(first).chain(other.powers.drain(..));
+        for (piece, next) in other.pieces.drain(..).zip(powers) {
+            self.merge_down(next);
+            self.pieces.push(piece);
+            self.powers.push(next);
+        }
+        self.last_run = other.last_run;
+    }
+    fn work(&self) -> Option<(&'static str, usize)> {
+        Some(("Sorting in place", self.data.len()))
+    }
+}
+
+/// Merges `data[..mid]` and `data[mid..]` in place. Every SymMerge step splits the merge into two
+/// smaller ones that don't overlap, so we keep those on a stack and give half of them away when
+/// we split.
+pub(crate) struct SymMerge<'f, T, F>
+where
+    F: Comparator<T>,
+{
+    data: *mut T,
+    merges: Vec<(usize, usize, usize)>, // merge data[start..mid] with data[mid..end]
+    work_size: usize,
+    split_factor: usize, // only split with more than split_factor * work_size work left
+    compare: &'f F,
+}
+unsafe impl<'f, T, F> Send for SymMerge<'f, T, F>
+where
+    T: Send,
+    F: Comparator<T> + Sync,
+{
+}
+
+impl<'f, T, F> SymMerge<'f, T, F>
+where
+    F: Comparator<T>,
+{
+    pub(crate) fn new(
+        data: &mut [T],
+        mid: usize,
+        work_size: usize,
+        split_factor: usize,
+        compare: &'f F,
+    ) -> SymMerge<'f, T, F> {
+        assert!(mid <= data.len());
+        SymMerge {
+            data: data.as_mut_ptr(),
+            merges: vec![(0, mid, data.len())],
+            work_size,
+            split_factor,
+            compare,
+        }
+    }
+    fn work_left(&self) -> usize {
+        self.merges.iter().map(|&(start, _, end)| end - start).sum()
+    }
+    fn less(&self, a: usize, b: usize) -> bool {
+        unsafe { self.compare.compare(&*self.data.add(a), &*self.data.add(b)) == Ordering::Less }
+    }
+    fn rotate(&mut self, start: usize, mid: usize, end: usize) {
+        unsafe { std::slice::from_raw_parts_mut(self.data.add(start), end - start) }
+            .rotate_left(mid - start);
+    }
+    /// One step of SymMerge: puts `start..mid` and `mid..end` in place around the middle of the
+    /// range, and pushes the two merges that are left on either side.
+    fn partition(&mut self, start: usize, mid: usize, end: usize) {
+        if start == mid || mid == end || !self.less(mid, mid - 1) {
+            // already in order
+            return;
+        }
+        if mid - start == 1 {
+            // insert the single element where it belongs, after any equal ones
+            let (mut low, mut high) = (mid, end);
+            while low < high {
+                let m = low + (high - low) / 2;
+                if self.less(m, start) {
+                    low = m + 1;
+                } else {
+                    high = m;
+                }
+            }
+            self.rotate(start, mid, low);
+            return;
+        }
+        if end - mid == 1 {
+            // insert the single element where it belongs, before any larger ones
+            let (mut low, mut high) = (start, mid);
+            while low < high {
+                let m = low + (high - low) / 2;
+                if self.less(mid, m) {
+                    high = m;
+                } else {
+                    low = m + 1;
+                }
+            }
+            self.rotate(low, mid, end);
+            return;
+        }
+        let half = start + (end - start) / 2;
+        let n = half + mid;
+        let (mut low, mut high) = if mid > half {
+            (n - end, half)
+        } else {
+            (start, mid)
+        };
+        let p = n - 1;
+        while low < high {
+            let c = low + (high - low) / 2;
+            if !self.less(p - c, c) {
+                low = c + 1;
+            } else {
+                high = c;
+            }
+        }
+        let (left_cut, right_cut) = (low, n - low);
+        if left_cut < mid && mid < right_cut {
+            self.rotate(left_cut, mid, right_cut);
+        }
+        self.merges.push((half, right_cut, end));
+        self.merges.push((start, left_cut, half));
+    }
+}
+
+impl<'f, T, F> Task for SymMerge<'f, T, F>
+where
+    T: Send,
+    F: Comparator<T> + Sync,
+{
+    fn step(&mut self) {
+        if self.compare.aborted() {
+            // nothing is in flight between the rotations, we can just stop
+            self.merges.clear();
+            return;
+        }
+        // small merges get done in one go
+        let target = self.work_left().saturating_sub(self.work_size);
+        while self.work_left() > target {
+            match self.merges.pop() {
+                Some((start, mid, end)) => self.partition(start, mid, end),
+                None => return,
+            }
+        }
+    }
+    fn is_finished(&self) -> bool {
+        self.merges.is_empty()
+    }
+    fn split(&mut self, mut runner: impl FnMut(&mut Vec<&mut Self>), _steal_counter: usize) {
+        // the merges at the bottom of the stack are the big ones, give away about half the work
+        let half = self.work_left() / 2;
+        let mut given = 0;
+        let mut count = 0;
+        while count < self.merges.len() - 1 && given < half {
+            given += self.merges[count].2 - self.merges[count].0;
+            count += 1;
+        }
+        let rest = self.merges.split_off(count);
+        let mut other = SymMerge {
+            data: self.data,
+            merges: std::mem::replace(&mut self.merges, rest),
+            work_size: self.work_size,
+            split_factor: self.split_factor,
+            compare: self.compare,
+        };
+        runner(&mut vec![self, &mut other]);
+    }
+    fn can_split(&self) -> bool {
+        self.merges.len() >= 2 && self.work_left() > self.work_size * self.split_factor
+    }
+    fn fuse(&mut self, _other: &mut Self) {
+        // Nothing to do here actually
+    }
+}
@@ -0,0 +1,555 @@
+use crate::compare::Comparator;
+use crate::slice_merge::clamp;
+use crate::MergesortConfig;
+use adaptive_algorithms::Task;
+use std::cmp::Ordering;
+use std::mem;
+use std::ptr;
+
+/// Merges the sorted slices in `inputs` into `out`, which must be exactly as long as all of them
+/// together. The merge is stable: equal elements come in the order of their inputs.
+pub fn merge_k<T>(inputs: &[&[T]], out: &mut [T])
+where
+    T: Ord + Copy + Send + Sync,
+{
+    merge_k_by(inputs, out, |a, b| a.cmp(b))
+}
+
+/// Like `merge_k`, with a comparator function.
+/// Inside a thread pool the merge gets split adaptively, otherwise it runs on the calling thread.
+pub fn merge_k_by<T, F>(inputs: &[&[T]], out: &mut [T], compare: F)
+where
+    T: Copy + Send + Sync,
+    F: Fn(&T, &T) -> Ordering + Sync,
+{
+    assert_eq!(
+        inputs.iter().map(|input| input.len()).sum::<usize>(),
+        out.len(),
+        "the output needs room for all inputs"
+    );
+    let config = MergesortConfig::default();
+    // see slice_merge::merge_into
+    let mut merge = KMerge::new(
+        inputs,
+        out,
+        config.merge_work_size,
+        config.split_factor,
+        &compare,
+    );
+    crate::run_anywhere(&mut merge);
+}
+
+/// Merges any number of inputs with a loser tree. Inputs may also sit in the output, each at its
+/// own place (see `with_spare`).
+pub struct KMerge<'f, T, F>
+where
+    F: Comparator<T>,
+{
+    pub inputs: Vec<(*const T, *const T)>, // start and end of what's left of each input
+    pub output: *mut T,
+    pub output_end: *const T,
+    pub output_limit: *const T, // everything from here on may stay unmerged
+    tree: Vec<usize>, // the losers of each match, tree[0] is the winner. Empty if it needs a rebuild
+    in_output: Vec<usize>, // the inputs that are in the output, in the order they sit there
+    spare: isize,     // bytes from any place in the output to the same place on the other side
+    pub work_size: usize,
+    pub split_factor: usize, // only split with more than split_factor * work_size work left
+    pub compare: &'f F,
+}
+unsafe impl<'f, T, F> Send for KMerge<'f, T, F>
+where
+    T: Send,
+    F: Comparator<T> + Sync,
+{
+}
+
+impl<'f, T, F> KMerge<'f, T, F>
+where
+    F: Comparator<T>,
+{
+    pub fn new(
+        inputs: &[&[T]],
+        output: &mut [T],
+        work_size: usize,
+        split_factor: usize,
+        compare: &'f F,
+    ) -> KMerge<'f, T, F> {
+        assert!(inputs.iter().map(|input| input.len()).sum::<usize>() == output.len());
+        KMerge {
+            inputs: inputs
+                .iter()
+                .map(|input| unsafe { (input.as_ptr(), input.as_ptr().add(input.len())) })
+                .collect(),
+            output: output.as_mut_ptr(),
+            output_end: unsafe { output.as_ptr().add(output.len()) },
+            output_limit: unsafe { output.as_ptr().add(output.len()) },
+            tree: Vec::new(),
+            in_output: Vec::new(),
+            spare: 0,
+            work_size,
+            split_factor,
+            compare,
+        }
+    }
+    /// Lets the inputs that are in the output stay where they are. `spare` is the distance in bytes
+    /// from the output to the same place on the other side, which has to be free where those inputs are. An input
+    /// only gets moved over there if the output is about to run over it, so mostly every element
+    /// moves once.
+    pub fn with_spare(mut self, spare: isize) -> Self {
+        let (start, end) = (self.output as *const T, self.output_end);
+        let inputs = &self.inputs;
+        self.in_output = (0..inputs.len())
+            .filter(|&i| inputs[i].0 < inputs[i].1 && start <= inputs[i].0 && inputs[i].1 <= end)
+            .collect();
+        self.in_output.sort_by_key(|&i| inputs[i].0 as usize);
+        self.spare = spare;
+        self
+    }
+    /// Only the first `limit` outputs need to be merged (see SliceMerge::with_limit).
+    pub fn with_limit(mut self, limit: usize) -> Self {
+        if self
+            .inputs
+            .iter()
+            .any(|&(start, end)| diff(start, end) > limit)
+        {
+            // the tails might run over the inputs in the output
+            self.clear_output(self.output_end);
+        }
+        unsafe {
+            let mut kept = 0;
+            let mut tail = self.output.add(diff(self.output, self.output_end));
+            // the tails go to the back in reverse, their order doesn't matter
+            for (start, end) in self.inputs.iter_mut().rev() {
+                let len = diff(*start, *end);
+                let keep = std::cmp::min(limit, len);
+                tail = tail.sub(len - keep);
+                ptr::copy_nonoverlapping(start.add(keep), tail, len - keep);
+                *end = start.add(keep);
+                kept += keep;
+            }
+            self.output_end = tail;
+            self.output_limit = self.output.add(std::cmp::min(limit, kept));
+        }
+        self
+    }
+    pub fn work_left(&self) -> usize {
+        // the step that reaches the limit stops right there
+        let limit = std::cmp::max(self.output as *const T, self.output_limit);
+        diff(self.output, limit)
+    }
+    /// The next element of input `i`, if there is one.
+    fn current(&self, i: usize) -> Option<&T> {
+        match self.inputs.get(i) {
+            Some(&(start, end)) if start < end => Some(unsafe { &*start }),
+            _ => None,
+        }
+    }
+    /// True if the next element of input `a` goes before the one of input `b`.
+    fn beats(&self, a: usize, b: usize) -> bool {
+        // finished inputs (and the padding up to a power of two) lose against everything, ties go
+        // to the earlier input
+        match (self.current(a), self.current(b)) {
+            (Some(x), Some(y)) => match self.compare.compare(x, y) {
+                Ordering::Less => true,
+                Ordering::Greater => false,
+                Ordering::Equal => a < b,
+            },
+            (Some(_), None) => true,
+            (None, Some(_)) => false,
+            (None, None) => a < b,
+        }
+    }
+    fn build_tree(&mut self) {
+        let leaves = self.inputs.len().next_power_of_two();
+        // winners[leaves + i] is leaf i, the tree above it holds the winner of each match
+        let mut winners = vec![0; 2 * leaves];
+        for (i, winner) in winners[leaves..].iter_mut().enumerate() {
+            *winner = i;
+        }
+        self.tree = vec![0; leaves];
+        for node in (1..leaves).rev() {
+            let (a, b) = (winners[2 * node], winners[2 * node + 1]);
+            let (winner, loser) = if self.beats(a, b) { (a, b) } else { (b, a) };
+            winners[node] = winner;
+            self.tree[node] = loser;
+        }
+        self.tree[0] = winners[1];
+    }
+    /// Replays the matches on the way from input `winner` to the root after it advanced.
+    fn replay(&mut self, mut winner: usize) {
+        let mut node = (self.tree.len() + winner) / 2;
+        while node >= 1 {
+            let loser = self.tree[node];
+            if self.beats(loser, winner) {
+                self.tree[node] = winner;
+                winner = loser;
+            }
+            node /= 2;
+        }
+        self.tree[0] = winner;
+    }
+    /// True if each input ends before the next one starts, ignoring empty ones.
+    fn in_order(&self) -> bool {
+        let mut last: Option<&T> = None;
+        for &(start, end) in &self.inputs {
+            if start == end {
+                continue;
+            }
+            unsafe {
+                if let Some(last) = last {
+                    if self.compare.compare(last, &*start) == Ordering::Greater {
+                        return false;
+                    }
+                }
+                last = Some(&*end.sub(1));
+            }
+        }
+        true
+    }
+    /// Moves what's left of input `i` out of the output, to the other side.
+    fn move_out(&mut self, i: usize) {
+        let (start, end) = self.inputs[i];
+        unsafe {
+            // the sides might be in different allocations, which needn't be a whole number of
+            // elements apart
+            let to = (start as *const u8).wrapping_offset(self.spare) as *const T;
+            ptr::copy_nonoverlapping(start, to as *mut T, diff(start, end));
+            self.inputs[i] = (to, to.add(diff(start, end)));
+        }
+        self.in_output.retain(|&j| j != i);
+    }
+    /// Moves the inputs in the output that start before `until` out of it.
+    fn clear_output(&mut self, until: *const T) {
+        while let Some(&i) = self.in_output.first() {
+            if self.inputs[i].0 >= until {
+                return;
+            }
+            self.move_out(i);
+        }
+    }
+    /// Makes sure writing the next output doesn't run over an input, unless it's the element of
+    /// `winner` that goes there anyway.
+    fn make_room(&mut self, winner: usize) {
+        while let Some(&i) = self.in_output.first() {
+            let (start, end) = self.inputs[i];
+            if start == end {
+                self.in_output.remove(0);
+            } else {
+                // the inputs in the output never start before it, so this is the only one in danger
+                if ptr::eq(start, self.output) && i != winner {
+                    self.move_out(i);
+                }
+                return;
+            }
+        }
+    }
+    /// With only two or three inputs left, lets the comparator merge whole blocks with its kernel
+    /// (see `Comparator::merge_blocks`). Returns how many elements it merged.
+    fn merge_blocks(&mut self) -> usize {
+        let work_size = std::cmp::min(self.work_size, self.work_left());
+        // the inputs that aren't finished, and their next `work_size` elements
+        let mut live = [0; 3];
+        let mut blocks = [(ptr::null(), ptr::null()); 3];
+        let mut count = 0;
+        for (i, &(start, end)) in self.inputs.iter().enumerate() {
+            if start < end {
+                if count == 3 {
+                    return 0;
+                }
+                live[count] = i;
+                blocks[count] = (start, unsafe {
+                    start.add(std::cmp::min(diff(start, end), work_size))
+                });
+                count += 1;
+            }
+        }
+        if count < 2 {
+            return 0;
+        }
+        // The kernel might write this far. An input in the output in front of that is left to
+        // the merge below, which moves it out of the way only if it has to.
+        let until = unsafe {
+            self.output.add(
+                blocks[..count]
+                    .iter()
+                    .map(|&(start, end)| diff(start, end))
+                    .sum(),
+            )
+        };
+        let inputs = &self.inputs;
+        if self
+            .in_output
+            .iter()
+            .any(|&i| inputs[i].0 < inputs[i].1 && inputs[i].0 < until)
+        {
+            return 0;
+        }
+        let output = self.output;
+        unsafe {
+            self.compare
+                .merge_blocks(&mut blocks[..count], &mut self.output)
+        };
+        for (&i, &(start, _)) in live.iter().zip(&blocks[..count]) {
+            self.inputs[i].0 = start;
+        }
+        diff(output, self.output)
+    }
+    /// Moves all remaining input elements to the output without merging them (see SliceMerge).
+    fn flush(&mut self) {
+        for i in 0..self.inputs.len() {
+            let (start, end) = self.inputs[i];
+            unsafe {
+                if self.in_output.contains(&i) {
+                    // it's further back in the output, or right here already
+                    self.clear_output(start);
+                    ptr::copy(start, self.output, diff(start, end));
+                } else {
+                    self.clear_output(self.output.add(diff(start, end)));
+                    ptr::copy_nonoverlapping(start, self.output, diff(start, end));
+                }
+                self.output = self.output.add(diff(start, end));
+            }
+            self.inputs[i].0 = end;
+        }
+        self.in_output.clear();
+    }
+}
+
+impl<'f, T, F> Task for KMerge<'f, T, F>
+where
+    T: Send,
+    F: Comparator<T> + Sync,
+{
+    fn step(&mut self) {
+        assert!(self.output as *const T != self.output_end);
+        if self.compare.aborted() || self.output as *const T >= self.output_limit {
+            self.flush();
+            return;
+        }
+        if self.tree.is_empty() && self.in_order() {
+            // just put them one after the other
+            self.flush();
+            return;
+        }
+        let merged = self.merge_blocks();
+        if merged > 0 || self.tree.is_empty() {
+            self.build_tree();
+        }
+        // there are as many input elements left as outputs, so the winner always has one
+        let work_size = self.work_size.saturating_sub(merged);
+        for _ in 0..std::cmp::min(work_size, self.work_left()) {
+            let winner = self.tree[0];
+            if !self.in_output.is_empty() {
+                self.make_room(winner);
+            }
+            unsafe {
+                let input = &mut self.inputs[winner].0;
+                // it might be in its place already
+                ptr::copy(*input, self.output, 1);
+                *input = input.add(1);
+                self.output = self.output.add(1);
+            }
+            self.replay(winner);
+        }
+    }
+    fn is_finished(&self) -> bool {
+        return diff(self.output, self.output_end) == 0;
+    }
+    fn split(&mut self, mut runner: impl FnMut(&mut Vec<&mut Self>), _steal_counter: usize) {
+        use std::slice::from_raw_parts;
+        let inputs: Vec<&[T]> = self
+            .inputs
+            .iter()
+            .map(|&(start, end)| unsafe { from_raw_parts(start, diff(start, end)) })
+            .collect();
+        let compare = self.compare;
+        let half = self.work_left() / 2;
+        let cuts = co_rank(&inputs, half, &|a, b| {
+            compare.compare(a, b) == Ordering::Less
+        });
+        let mut other = KMerge {
+            inputs: inputs
+                .iter()
+                .zip(&cuts)
+                .map(|(input, &cut)| unsafe {
+                    (input.as_ptr().add(cut), input.as_ptr().add(input.len()))
+                })
+                .collect(),
+            output: unsafe { self.output.add(half) },
+            output_end: self.output_end,
+            output_limit: clamp(
+                self.output_limit,
+                unsafe { self.output.add(half) },
+                self.output_end,
+            ),
+            tree: Vec::new(),
+            in_output: Vec::new(),
+            spare: self.spare,
+            work_size: self.work_size,
+            split_factor: self.split_factor,
+            compare: self.compare,
+        };
+        for (input, cut) in self.inputs.iter_mut().zip(cuts) {
+            input.1 = unsafe { input.0.add(cut) };
+        }
+        // The inputs in the output are split too. A part that's in the way of the other task's
+        // output moves out, the rest stays where it is.
+        let middle = other.output as *const T;
+        for i in std::mem::take(&mut self.in_output) {
+            let (start, cut) = self.inputs[i];
+            let end = other.inputs[i].1;
+            if cut < end {
+                if cut < middle {
+                    other.move_out(i);
+                } else {
+                    other.in_output.push(i);
+                }
+            }
+            if start < cut {
+                if cut > middle {
+                    self.move_out(i);
+                } else {
+                    self.in_output.push(i);
+                }
+            }
+        }
+        self.output_end = other.output;
+        self.output_limit = std::cmp::min(self.output_limit, self.output_end);
+        // some inputs might be finished now
+        self.tree.clear();
+        runner(&mut vec![self, &mut other]);
+    }
+    fn can_split(&self) -> bool {
+        return self.work_left() > self.work_size * self.split_factor;
+    }
+    fn fuse(&mut self, _other: &mut Self) {
+        // Nothing to do here actually
+    }
+}
+
+impl<'f, T, F> Drop for KMerge<'f, T, F>
+where
+    F: Comparator<T>,
+{
+    fn drop(&mut self) {
+        // we only get here unfinished if a comparison panicked
+        if self.output as *const T != self.output_end {
+            self.flush();
+        }
+    }
+}
+
+/// Finds a cut in each input so that exactly `rank` elements are in front of the cuts, and they
+/// all come before the elements behind the cuts in the merged output.
+pub(crate) fn co_rank<T, F>(inputs: &[&[T]], rank: usize, is_less: &F) -> Vec<usize>
+where
+    F: Fn(&T, &T) -> bool,
+{
+    // the cut of input i is somewhere in lo[i]..=hi[i]
+    let mut lo = vec![0; inputs.len()];
+    let mut hi: Vec<usize> = inputs.iter().map(|input| input.len()).collect();
+    loop {
+        // take the pivot from the middle of the widest range
+        let j = match (0..inputs.len())
+            .filter(|&i| lo[i] < hi[i])
+            .max_by_key(|&i| hi[i] - lo[i])
+        {
+            Some(j) => j,
+            None => return lo,
+        };
+        let m = lo[j] + (hi[j] - lo[j]) / 2;
+        let pivot = &inputs[j][m];
+        // Where the pivot would go in each input, clamped to the ranges. Equal elements of earlier
+        // inputs come before it, the ones of later inputs after it.
+        let ranks: Vec<usize> = (0..inputs.len())
+            .map(|i| {
+                let range = &inputs[i][lo[i]..hi[i]];
+                lo[i]
+                    + match i.cmp(&j) {
+                        Ordering::Less => range.partition_point(|x| !is_less(pivot, x)),
+                        Ordering::Equal => m - lo[i],
+                        Ordering::Greater => range.partition_point(|x| is_less(x, pivot)),
+                    }
+            })
+            .collect();
+        let before: usize = ranks.iter().sum();
+        if before == rank {
+            return ranks;
+        } else if before < rank {
+            // the pivot is in front of the cuts, and so is everything before it
+            lo = ranks;
+            lo[j] = m + 1;
+        } else {
+            // the pivot is behind the cuts, and so is everything after it
+            hi = ranks;
+        }
+    }
+}
+
+// difference between two pointer (it's in  std::ptr but only on nightly)
+fn diff<T>(left: *const T, right: *const T) -> usize {
+    (right as usize - left as usize) / mem::size_of::<T>()
+}
+
+// merges the leftovers like merge_rest does, with the biggest piece at the front of the output
+// and the others on the other side, and checks that the kernel does most of the work
+#[test]
+pub fn merge_blocks_with_spare() {
+    use std::sync::atomic::{AtomicUsize, Ordering as AtomicOrdering};
+    // a kernel that merges one element at a time while every input has two left, and counts them
+    struct Counted(AtomicUsize);
+    impl Comparator<u32> for Counted {
+        fn compare(&self, a: &u32, b: &u32) -> Ordering {
+            a.cmp(b)
+        }
+        unsafe fn merge_blocks(
+            &self,
+            inputs: &mut [(*const u32, *const u32)],
+            output: &mut *mut u32,
+        ) {
+            while inputs.iter().all(|&(start, end)| diff(start, end) >= 2) {
+                let (mut first, _) = inputs[0];
+                let mut winner = 0;
+                for (i, &(start, _)) in inputs.iter().enumerate() {
+                    if *start < *first {
+                        first = start;
+                        winner = i;
+                    }
+                }
+                ptr::copy_nonoverlapping(first, *output, 1);
+                inputs[winner].0 = first.add(1);
+                *output = output.add(1);
+                self.0.fetch_add(1, AtomicOrdering::Relaxed);
+            }
+        }
+    }
+    for &lengths in &[[60_000, 30_000, 10_000], [60_000, 40_000, 0]] {
+        let sorted = |len| {
+            let mut v: Vec<u32> = std::iter::repeat_with(rand::random).take(len).collect();
+            v.sort();
+            v
+        };
+        let len = lengths.iter().sum();
+        let mut out = sorted(lengths[0]);
+        out.resize(len, 0);
+        let mut other = vec![0; len];
+        other[lengths[0]..lengths[0] + lengths[1]].copy_from_slice(&sorted(lengths[1]));
+        other[lengths[0] + lengths[1]..].copy_from_slice(&sorted(lengths[2]));
+        let mut expected = [&out[..lengths[0]], &other[lengths[0]..]].concat();
+        expected.sort();
+        let compare = Counted(AtomicUsize::new(0));
+        {
+            let (second, third) = other[lengths[0]..].split_at(lengths[1]);
+            let inputs = [
+                unsafe { std::slice::from_raw_parts(out.as_ptr(), lengths[0]) },
+                second,
+                third,
+            ];
+            let spare = other.as_ptr() as isize - out.as_ptr() as isize;
+            let mut merge = KMerge::new(&inputs, &mut out, 100, 4, &compare).with_spare(spare);
+            merge.run();
+        }
+        assert_eq!(out, expected);
+        assert!(compare.0.load(AtomicOrdering::Relaxed) > len / 10 * 9);
+    }
+}
@@ -1,12 +1,29 @@
 #[macro_use]
 extern crate lazy_static;
+mod cached_key;
+pub(crate) mod compare;
+mod config;
+mod in_place;
+mod k_merge;
 pub mod merge;
+mod policy;
 // pub mod rayon;
+mod simd;
 mod slice_merge;
+mod sorter;
 pub mod steal;
 mod three_merge;
 // pub mod task;
+use compare::Comparator;
+pub use config::{ConfigError, MergesortConfig};
+pub use k_merge::{merge_k, merge_k_by};
 use rand::prelude::*;
+pub use simd::{mergesort_keys, par_merge_keys, Key};
+pub use slice_merge::{par_merge, par_merge_by, par_merge_with_branches};
+pub use sorter::Sorter;
+use std::cmp::Ordering;
+use std::mem::MaybeUninit;
+use std::ptr;
 
 use adaptive_algorithms::rayon;
 use adaptive_algorithms::Task;
@@ -65,58 +82,397 @@ pub fn test_with(num_elements: usize) {
 
 pub fn mergesort<T>(data: &mut [T])
 where
-    T: Ord + Sync + Send + Copy,
+    T: Ord + Send,
 {
+    mergesort_by(data, |a, b| a.cmp(b))
+}
+
+/// Sorts `data` with a comparator function, like `slice::sort_by`.
+/// The sort is stable: equal elements keep their relative order.
+pub fn mergesort_by<T, F>(data: &mut [T], compare: F)
+where
+    T: Send,
+    F: Fn(&T, &T) -> Ordering + Sync,
+{
+    mergesort_with(data, &compare, &MergesortConfig::default())
+}
+
+/// Sorts `data` with a fallible comparator function.
+/// The first error stops all sorting tasks and gets returned. `data` is then left in an
+/// unspecified order, but still holds all the elements.
+pub fn try_mergesort_by<T, E, F>(data: &mut [T], compare: F) -> Result<(), E>
+where
+    T: Send,
+    E: Send,
+    F: Fn(&T, &T) -> Result<Ordering, E> + Sync,
+{
+    let compare = compare::TryCompare::new(compare);
+    mergesort_with(data, &compare, &MergesortConfig::default());
+    compare.into_result()
+}
+
+/// Sorts the `k` smallest elements of `data` into `data[..k]`, in the same order `mergesort`
+/// would put them. The rest ends up in `data[k..]` in unspecified order.
+pub fn mergesort_partial<T>(data: &mut [T], k: usize)
+where
+    T: Ord + Send,
+{
+    mergesort_partial_by(data, k, |a, b| a.cmp(b))
+}
+
+/// Like `mergesort_partial`, with a comparator function.
+/// The merges stop once their first `k` outputs are final, so this is cheaper for small `k`.
+pub fn mergesort_partial_by<T, F>(data: &mut [T], k: usize, compare: F)
+where
+    T: Send,
+    F: Fn(&T, &T) -> Ordering + Sync,
+{
+    if k == 0 {
+        return;
+    }
+    let config = MergesortConfig {
+        limit: k,
+        ..MergesortConfig::default()
+    };
+    mergesort_with(data, &compare, &config)
+}
+
+/// Sorts `data`, which is made of sorted runs that end at the indices in `boundaries`.
+/// Then all that's left to do is merging the runs. Runs that turn out not to be sorted get
+/// sorted first.
+pub fn mergesort_runs<T>(data: &mut [T], boundaries: &[usize])
+where
+    T: Ord + Send,
+{
+    mergesort_by_runs(data, boundaries, |a, b| a.cmp(b))
+}
+
+/// Like `mergesort_runs`, with a comparator function.
+pub fn mergesort_by_runs<T, F>(data: &mut [T], boundaries: &[usize], compare: F)
+where
+    T: Send,
+    F: Fn(&T, &T) -> Ordering + Sync,
+{
+    assert!(
+        boundaries.windows(2).all(|w| w[0] < w[1])
+            && boundaries.iter().all(|&boundary| boundary <= data.len()),
+        "the run boundaries need to be increasing and inside the data"
+    );
+    if data.len() <= 1 {
+        return;
+    }
+    // see mergesort_with
     let mut tmp_slice: Vec<T> = Vec::with_capacity(data.len());
-    unsafe { tmp_slice.set_len(data.len()) }
-    let data_ptr = data.as_mut_ptr();
-    let len = data.len();
-    let mut mergesort = Mergesort {
+    let to = unsafe { std::slice::from_raw_parts_mut(tmp_slice.as_mut_ptr(), data.len()) };
+    let config = MergesortConfig::default();
+    sort_into(data, to, &compare, &config, None, Some(boundaries));
+}
+
+/// Runs `task` adaptively inside a thread pool, and on the calling thread otherwise.
+pub(crate) fn run_anywhere(task: &mut impl Task) {
+    if ::rayon::current_thread_index().is_some() {
+        task.run();
+    } else {
+        while !task.is_finished() {
+            task.step();
+        }
+    }
+}
+
+// the configuration needs to be valid
+fn mergesort_with<T, F>(data: &mut [T], compare: &F, config: &MergesortConfig)
+where
+    T: Send,
+    F: Comparator<T> + Sync,
+{
+    if data.len() <= 1 {
+        return;
+    }
+    if config.in_place {
+        return in_place::sort_in_place(data, compare, config);
+    }
+    if config.half_buffer {
+        let mut tmp_slice: Vec<T> = Vec::with_capacity(data.len() - data.len() / 2);
+        let to = unsafe {
+            std::slice::from_raw_parts_mut(tmp_slice.as_mut_ptr(), data.len() - data.len() / 2)
+        };
+        return sort_half_buffer(data, to, compare, config);
+    }
+    // Scratch space. We only ever move elements between `data` and the buffer with ptr::copy, so
+    // every element lives in exactly one of them. The Vec keeps a length of 0, so it never drops
+    // anything, even if we panic.
+    let mut tmp_slice: Vec<T> = Vec::with_capacity(data.len());
+    let to = unsafe { std::slice::from_raw_parts_mut(tmp_slice.as_mut_ptr(), data.len()) };
+    sort_into(data, to, compare, config, None, None);
+}
+
+/// Sorts `data` with a scratch buffer `to` of half its size (rounded up): first both halves with
+/// the buffer as scratch space, then they get merged by moving the left half into the buffer and
+/// merging it with the right half from the front.
+fn sort_half_buffer<T, F>(data: &mut [T], to: &mut [T], compare: &F, config: &MergesortConfig)
+where
+    T: Send,
+    F: Comparator<T> + Sync,
+{
+    let mid = data.len() / 2;
+    // the right half is the bigger one
+    assert_eq!(to.len(), data.len() - mid);
+    let (left, right) = data.split_at_mut(mid);
+    sort_into(left, &mut to[..mid], compare, config, None, None);
+    sort_into(right, to, compare, config, None, None);
+    if compare.aborted() {
+        return;
+    }
+    unsafe { ptr::copy_nonoverlapping(data.as_ptr(), to.as_mut_ptr(), mid) };
+    // if a comparison panics, the merge puts everything that's left back into the data
+    let mut merge = slice_merge::SliceMerge::half_copy(
+        &to[..mid],
         data,
-        to: &mut tmp_slice,
-        pieces: Vec::new(),
-        blocksize: 81,
+        config.merge_work_size,
+        config.split_factor,
+        compare,
+    );
+    merge.run();
+}
+
+/// How `mergesort_with_budget` sorted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Strategy {
+    /// With a scratch buffer as big as the data.
+    FullBuffer,
+    /// With a scratch buffer half the size of the data, see `MergesortConfig::half_buffer`.
+    HalfBuffer,
+    /// Without a scratch buffer, see `MergesortConfig::in_place`.
+    InPlace,
+}
+
+/// Sorts `data` like `mergesort`, with a scratch buffer of at most `max_extra_bytes`. It takes the
+/// fastest strategy that fits and tells which one that was. Apart from the scratch buffer there's
+/// only a few small allocations, like a block's worth for sorting the leaves.
+pub fn mergesort_with_budget<T>(data: &mut [T], max_extra_bytes: usize) -> Strategy
+where
+    T: Ord + Send,
+{
+    mergesort_by_with_budget(data, max_extra_bytes, |a, b| a.cmp(b))
+}
+
+/// Like `mergesort_with_budget`, with a comparator function.
+pub fn mergesort_by_with_budget<T, F>(
+    data: &mut [T],
+    max_extra_bytes: usize,
+    compare: F,
+) -> Strategy
+where
+    T: Send,
+    F: Fn(&T, &T) -> Ordering + Sync,
+{
+    let fits = |len: usize| match len.checked_mul(std::mem::size_of::<T>()) {
+        Some(bytes) => bytes <= max_extra_bytes,
+        None => false,
     };
-    mergesort.run();
-    // There might be many ordered non-sorted blocks left. That happens when we sort an input
-    // that's not a power of two elements.
-    assert!(
-        mergesort.pieces_len().windows(2).all(|w| w[0] >= w[1]),
-        format!("{:?}", mergesort.pieces_len())
+    let len = data.len();
+    let (strategy, config) = if fits(len) {
+        (Strategy::FullBuffer, MergesortConfig::default())
+    } else if fits(len - len / 2) {
+        (
+            Strategy::HalfBuffer,
+            MergesortConfig::default().half_buffer(true),
+        )
+    } else {
+        (Strategy::InPlace, MergesortConfig::default().in_place(true))
+    };
+    mergesort_with(data, &compare, &config);
+    strategy
+}
+
+/// Sorts `data` like `mergesort`, but uses `scratch` instead of allocating a buffer.
+/// `scratch` needs to hold at least `data.len()` elements, its content doesn't matter.
+pub fn mergesort_with_buffer<T>(
+    data: &mut [T],
+    scratch: &mut [MaybeUninit<T>],
+) -> Result<(), BufferTooSmall>
+where
+    T: Ord + Send,
+{
+    mergesort_by_with_buffer(data, scratch, |a, b| a.cmp(b))
+}
+
+/// Like `mergesort_with_buffer`, with a comparator function.
+pub fn mergesort_by_with_buffer<T, F>(
+    data: &mut [T],
+    scratch: &mut [MaybeUninit<T>],
+    compare: F,
+) -> Result<(), BufferTooSmall>
+where
+    T: Send,
+    F: Fn(&T, &T) -> Ordering + Sync,
+{
+    if scratch.len() < data.len() {
+        return Err(BufferTooSmall {
+            needed: data.len(),
+            got: scratch.len(),
+        });
+    }
+    if data.len() <= 1 {
+        return Ok(());
+    }
+    // we only ever move elements through the scratch memory, so it's fine that it's uninitialized
+    let to = unsafe { std::slice::from_raw_parts_mut(scratch.as_mut_ptr() as *mut T, data.len()) };
+    sort_into(data, to, &compare, &MergesortConfig::default(), None, None);
+    Ok(())
+}
+
+/// The scratch buffer passed to `mergesort_with_buffer` is shorter than the data.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BufferTooSmall {
+    pub needed: usize,
+    pub got: usize,
+}
+
+impl std::fmt::Display for BufferTooSmall {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "scratch buffer too small: need {} elements, got {}",
+            self.needed, self.got
+        )
+    }
+}
+
+impl std::error::Error for BufferTooSmall {}
+
+/// Clones `src` into `dst` and sorts it there, `src` stays untouched.
+/// `dst` needs to be as long as `src`. It holds initialized, sorted elements afterwards (unless a
+/// comparison or a clone panics, then its content is unspecified and nothing gets dropped).
+pub fn mergesort_into<T>(src: &[T], dst: &mut [MaybeUninit<T>])
+where
+    T: Ord + Clone + Send + Sync,
+{
+    mergesort_by_into(src, dst, |a, b| a.cmp(b))
+}
+
+/// Like `mergesort_into`, with a comparator function.
+pub fn mergesort_by_into<T, F>(src: &[T], dst: &mut [MaybeUninit<T>], compare: F)
+where
+    T: Clone + Send + Sync,
+    F: Fn(&T, &T) -> Ordering + Sync,
+{
+    assert_eq!(
+        src.len(),
+        dst.len(),
+        "destination needs to be as long as the source"
     );
-    // println!("{:?}", mergesort.pieces_len());
-    // let's merge all the pieces from the back
-    while mergesort.pieces.len() >= 2 {
-        let mut other = mergesort.pieces.pop().unwrap();
-        let me = mergesort.pieces.last_mut().unwrap();
-        unsafe {
-            if me.data.as_ptr().add(me.data.len()) != other.data.as_ptr() {
-                // one piece has it's result in the data and the other in the memory. We need to
-                // copy one over (it's better to choose the smaller piece
-                std::ptr::copy_nonoverlapping(
-                    other.data.as_ptr(),
-                    other.buffer.as_mut_ptr(),
-                    other.data.len(),
-                );
-                std::mem::swap(&mut other.data, &mut other.buffer);
-            }
+    let len = src.len();
+    let data = unsafe { std::slice::from_raw_parts_mut(dst.as_mut_ptr() as *mut T, len) };
+    let load = |offset: usize, target: &mut [T]| {
+        for (i, x) in src[offset..offset + target.len()].iter().enumerate() {
+            unsafe { ptr::write(target.as_mut_ptr().add(i), x.clone()) }
         }
-        mergesort.pieces.last_mut().unwrap().merge(other);
-    }
-    assert!(mergesort.data.windows(2).all(|w| w[0] <= w[1]));
-    // we need to check where the output landed, it's either in the original data or in the
-    // buffer. If it's in the buffer, we need to copy it over
-    if data_ptr != mergesort.pieces[0].data.as_mut_ptr() {
-        // rayon::subgraph("merging", tmp_slice.len(), ||
-        unsafe {
-            std::ptr::copy_nonoverlapping(tmp_slice.as_ptr(), data_ptr, len);
-        } // );
     };
-    // keep the buffer size to 0 so it doesn't deallocate anything
-    // see : https://doc.rust-lang.org/src/alloc/slice.rs.html#966
+    let config = MergesortConfig::default();
+    if len <= 1 {
+        load(0, data);
+        return;
+    }
+    let mut tmp_slice: Vec<T> = Vec::with_capacity(len);
+    let to = unsafe { std::slice::from_raw_parts_mut(tmp_slice.as_mut_ptr(), len) };
+    sort_into(data, to, &compare, &config, Some(&load), None);
+}
+
+/// Returns a sorted clone of `src`.
+pub fn mergesort_to_vec<T>(src: &[T]) -> Vec<T>
+where
+    T: Ord + Clone + Send + Sync,
+{
+    let mut v: Vec<T> = Vec::with_capacity(src.len());
     unsafe {
-        tmp_slice.set_len(0);
+        let dst = std::slice::from_raw_parts_mut(v.as_mut_ptr() as *mut MaybeUninit<T>, src.len());
+        mergesort_into(src, dst);
+        v.set_len(src.len());
     }
+    v
+}
+
+/// Sorts `data` (or whatever `load` puts there), using `to` as scratch space of the same size.
+/// With `runs`, the data gets sorted in natural mode along the given run boundaries.
+/// Afterwards the result is in `data`.
+fn sort_into<'a, T, F>(
+    data: &'a mut [T],
+    to: &'a mut [T],
+    compare: &'a F,
+    config: &MergesortConfig,
+    load: Option<&'a Load<'a, T>>,
+    runs: Option<&'a [usize]>,
+) where
+    T: Send,
+    F: Comparator<T> + Sync,
+{
+    let len = data.len();
+    // With fixed blocks we know the whole merge tree up front. If the last merge would write into
+    // the buffer, the leaves go there instead, then it writes into `data` and we save copying
+    // everything back.
+    let planned = runs.is_none() && !config.natural;
+    let leaves_in_buffer = planned && policy::lands_in_buffer(len, config.arity, config.blocksize);
+    let mut mergesort = Mergesort {
+        data,
+        to,
+        pieces: Vec::new(),
+        compare,
+        config: *config,
+        offset: 0,
+        load,
+        leaves_in_buffer,
+        runs,
+        powers: Vec::new(),
+        first_run: None,
+        last_run: None,
+        len,
+        merging: false,
+        stolen: Vec::new(),
+    };
+    mergesort.run();
+    mergesort.merge_rest();
+    if compare.aborted() {
+        // dropping the task moves all pieces back into the data
+        return;
+    }
+    assert_eq!(mergesort.pieces.len(), 1, "{:?}", mergesort.pieces_len());
+    debug_assert!(compare.aborted() || mergesort.pieces[0].is_sorted_by(compare));
+    // with runs we couldn't plan ahead, the output might be in the buffer and we need to copy it
+    // over
+    debug_assert!(!(planned && mergesort.pieces[0].in_buffer));
+    // rayon::subgraph("merging", tmp_slice.len(), ||
+    mergesort.pieces[0].restore();
+    // );
+}
+
+/// Sorts `data` by the key `f` returns, like `slice::sort_by_key`.
+/// The key is recomputed for every comparison, see `mergesort_by_cached_key` for expensive keys.
+pub fn mergesort_by_key<T, K, F>(data: &mut [T], f: F)
+where
+    T: Send,
+    K: Ord,
+    F: Fn(&T) -> K + Sync,
+{
+    mergesort_by(data, |a, b| f(a).cmp(&f(b)))
+}
+
+/// Sorts `data` by the key `f` returns, like `slice::sort_by_cached_key`.
+/// The keys are computed once, in parallel, then the (key, index) pairs get sorted and `data` is
+/// permuted accordingly.
+pub fn mergesort_by_cached_key<T, K, F>(data: &mut [T], f: F)
+where
+    T: Sync + Send,
+    K: Ord + Send,
+    F: Fn(&T) -> K + Sync,
+{
+    let len = data.len();
+    let mut keys: Vec<(K, usize)> = Vec::with_capacity(len);
+    cached_key::ComputeKeys::new(data, keys.as_mut_ptr(), &f).run();
+    unsafe { keys.set_len(len) }
+    // the sort is stable, so we don't need to compare the indices
+    mergesort_by(&mut keys, |a, b| a.0.cmp(&b.0));
+    cached_key::permute(data, &mut keys);
 }
 // from https://stackoverflow.com/questions/42162151/rust-error-e0495-using-split-at-mut-in-a-closure
 pub fn cut_off_left<'a, T>(s: &mut &'a mut [T], mid: usize) -> &'a mut [T] {
@@ -132,51 +488,162 @@ pub fn cut_off_right<'a, T>(s: &mut &'a mut [T], mid: usize) -> &'a mut [T] {
     right
 }
 
-struct Mergesort<'a, T>
+struct Mergesort<'a, T, F>
 where
-    T: Ord + Sync + Send + Copy,
+    T: Send,
+    F: Comparator<T> + Sync,
 {
     data: &'a mut [T],
     to: &'a mut [T],
     pieces: Vec<merge::MergeResult<'a, T>>,
-    blocksize: usize,
+    compare: &'a F,
+    config: MergesortConfig,
+    offset: usize, // index of data[0] in the whole input
+    load: Option<&'a Load<'a, T>>,
+    leaves_in_buffer: bool,
+    runs: Option<&'a [usize]>,         // run boundaries given by the caller
+    powers: Vec<u32>,                  // powers of the boundaries between the pieces (see policy)
+    first_run: Option<(usize, usize)>, // start and end of the first and last run we sorted
+    last_run: Option<(usize, usize)>,
+    len: usize, // length of the whole input
+    merging: bool,
+    stolen: Vec<Mergesort<'a, T, F>>, // tasks that split off during a merge, see fuse
 }
-impl<'a, T> Mergesort<'a, T>
+
+/// Writes the input elements starting at the given index into the (uninitialized) slice.
+type Load<'a, T> = dyn Fn(usize, &mut [T]) + Sync + 'a;
+
+impl<'a, T, F> Mergesort<'a, T, F>
 where
-    T: Ord + Sync + Send + Copy,
+    T: Send,
+    F: Comparator<T> + Sync,
 {
     fn pieces_len(&self) -> Vec<usize> {
         // mostly for debugging
         self.pieces.iter().map(|x| x.len()).collect()
     }
-    fn merge_three(&mut self)
-    where
-        T: Ord + Sync + Send + Copy,
-    {
-        while self.pieces.len() >= 3 {
-            // to merge we need at least two parts, they need to be same size
-            let len = self.pieces.len();
-            let a = &self.pieces[len - 3];
-            let b = &self.pieces[len - 2];
-            let c = &self.pieces[len - 1];
-            if a.len() == b.len() && a.len() == c.len() {
-                // we can merge, remove last item
-
-                let c: merge::MergeResult<'a, T> = self.pieces.pop().unwrap();
-                let b: merge::MergeResult<'a, T> = self.pieces.pop().unwrap();
-                let a: &mut merge::MergeResult<'a, T> = &mut self.pieces.last_mut().unwrap();
-                // we want to be able to work on this element while also working on the merge at
-                // the same time. There should be a better that disabling the borrow checker here,
-                // but it works for now
-                let a: &mut merge::MergeResult<'a, T> = unsafe { std::mem::transmute(a) };
-
-                // rayon::subgraph("merging", a.len() + b.len(), || a.merge(b, Some(self)));
-                // a.merge(b, adaptive_algorithms::task::NOTHING);
-                a.merge_three(b, c, self);
-            } else {
-                break; // nothing to do
+    fn natural(&self) -> bool {
+        self.config.natural || self.runs.is_some()
+    }
+    /// Natural mode: finds the run at the start of the data and makes sure it's sorted. Returns
+    /// its length.
+    fn next_run(&mut self) -> usize {
+        let compare = self.compare;
+        let less = |a: &T, b: &T| compare.compare(a, b) == Ordering::Less;
+        let offset = self.offset;
+        let data = &mut *self.data;
+        let mut len = 1;
+        if let Some(runs) = self.runs {
+            // a split might have cut the run short
+            let next = runs.partition_point(|&boundary| boundary <= offset);
+            len = runs
+                .get(next)
+                .map_or(data.len(), |&boundary| boundary - offset)
+                .min(data.len());
+            // we don't trust the caller, checking is cheap enough
+            if !data[..len].windows(2).all(|w| !less(&w[1], &w[0])) {
+                compare.sort_leaf(&mut data[..len]);
+            }
+            return len;
+        }
+        if data.len() >= 2 && less(&data[1], &data[0]) {
+            // strictly descending, so reversing it keeps the sort stable
+            while len < data.len() && less(&data[len], &data[len - 1]) {
+                len += 1;
+            }
+            data[..len].reverse();
+        } else {
+            while len < data.len() && !less(&data[len], &data[len - 1]) {
+                len += 1;
+            }
+        }
+        if len < self.config.blocksize {
+            // not worth merging, sort a whole block instead
+            len = std::cmp::min(self.config.blocksize, data.len());
+            compare.sort_leaf(&mut data[..len]);
+        }
+        len
+    }
+    /// Puts a sorted run on the stack, after merging whatever the policy wants merged first.
+    fn push(&mut self, piece: merge::MergeResult<'a, T>, run: (usize, usize)) {
+        match self.last_run {
+            Some(last) => {
+                let next = policy::power(self.len, self.config.arity, last, run);
+                self.merge_down(next);
+                self.powers.push(next);
+            }
+            None => self.first_run = Some(run),
+        }
+        self.pieces.push(piece);
+        self.last_run = Some(run);
+    }
+    /// Merges pieces until the boundary with power `next` can go on the stack.
+    fn merge_down(&mut self, next: u32) {
+        while !self.compare.aborted() {
+            match policy::next_merge(&self.powers, next, self.config.arity) {
+                Some(count) => self.merge_top(count),
+                None => return,
+            }
+        }
+    }
+    /// Moves the pieces of this finished task into a new one, leaving this one empty.
+    fn take_pieces(&mut self) -> Self {
+        Mergesort {
+            data: &mut [],
+            to: &mut [],
+            pieces: std::mem::take(&mut self.pieces),
+            compare: self.compare,
+            config: self.config,
+            offset: self.offset,
+            load: self.load,
+            leaves_in_buffer: self.leaves_in_buffer,
+            runs: self.runs,
+            powers: std::mem::take(&mut self.powers),
+            first_run: self.first_run.take(),
+            last_run: self.last_run.take(),
+            len: self.len,
+            merging: false,
+            stolen: std::mem::take(&mut self.stolen),
+        }
+    }
+    /// Merges everything that's left on the stack at once, that way it's a single parallel pass
+    /// over the data instead of a cascade of ever bigger merges.
+    fn merge_rest(&mut self) {
+        if self.pieces.len() >= 2 && !self.compare.aborted() {
+            self.merge_top(self.pieces.len());
+        }
+    }
+    /// Merges the top `count` pieces, up to the arity (or all of them, see `merge_rest`).
+    fn merge_top(&mut self, count: usize) {
+        let first = self.pieces.len() - count;
+        self.powers.truncate(self.powers.len() + 1 - count);
+        // One piece might have its result in the data and another one in the buffer. The result
+        // goes to the other side of the biggest one, and if some are on that side already the
+        // k-way merge reads them from there.
+        let lengths: Vec<usize> = self.pieces[first..].iter().map(|x| x.len()).collect();
+        let side = self.pieces[first + policy::keeps_side(&lengths)].in_buffer;
+        let mixed = self.pieces[first..]
+            .iter()
+            .any(|piece| piece.in_buffer != side);
+        let mut others = self.pieces.split_off(first + 1);
+        let a: &mut merge::MergeResult<'a, T> = self.pieces.last_mut().unwrap();
+        // we want to be able to work on this element while also working on the merge at
+        // the same time. There should be a better that disabling the borrow checker here,
+        // but it works for now
+        let a: &mut merge::MergeResult<'a, T> = unsafe { std::mem::transmute(a) };
+
+        // rayon::subgraph("merging", a.len() + b.len(), || a.merge(b, Some(self)));
+        self.merging = true;
+        match count {
+            2 if !mixed => a.merge_with(others.pop().unwrap(), self.compare, self),
+            3 if !mixed => {
+                let c = others.pop().unwrap();
+                let b = others.pop().unwrap();
+                a.merge_three(b, c, self.compare, self)
             }
+            _ => a.merge_many(others, !side, self.compare, self),
         }
+        self.merging = false;
     }
     /*
     fn merge(&mut self)
@@ -231,30 +698,110 @@ where
        }
      */
 }
-impl<'a, T> Task for Mergesort<'a, T>
+/// Moves a leaf back into the data if merging it into the buffer panics.
+struct MoveBack<T> {
+    from: *const T,
+    to: *mut T,
+    len: usize,
+}
+impl<T> Drop for MoveBack<T> {
+    fn drop(&mut self) {
+        unsafe { ptr::copy_nonoverlapping(self.from, self.to, self.len) };
+    }
+}
+impl<'a, T, F> Drop for Mergesort<'a, T, F>
 where
-    T: Ord + Sync + Send + Copy,
+    T: Send,
+    F: Comparator<T> + Sync,
+{
+    fn drop(&mut self) {
+        // Normally all pieces got fused into the first task by now. If a comparison panicked, we
+        // still need to move our sorted pieces out of the scratch buffer, so that the input holds
+        // all the elements again.
+        for piece in self.pieces.iter_mut() {
+            piece.restore();
+        }
+    }
+}
+impl<'a, T, F> Task for Mergesort<'a, T, F>
+where
+    T: Send,
+    F: Comparator<T> + Sync,
 {
     fn step(&mut self) {
-        // this seems to be required after a split sometimes
-        self.merge_three();
-
         let elem_left = self.data.len();
         if elem_left == 0 {
             return;
         };
+        if self.compare.aborted() {
+            // leave the rest of the data where it is, unsorted
+            self.data = &mut [];
+            self.to = &mut [];
+            return;
+        }
         // Do some work: Split off and sort piece
-        let work_size = std::cmp::min(self.blocksize, elem_left);
-        let piece = cut_off_left(&mut self.data, work_size);
+        let natural = self.natural();
+        let work_size = if natural {
+            self.next_run()
+        } else {
+            std::cmp::min(self.config.blocksize, elem_left)
+        };
+        let mut piece = cut_off_left(&mut self.data, work_size);
+        let mut buffer = cut_off_left(&mut self.to, work_size);
+        if let Some(load) = self.load {
+            if self.leaves_in_buffer {
+                std::mem::swap(&mut piece, &mut buffer);
+            }
+            load(self.offset, piece);
+        }
+        let run = (self.offset, self.offset + work_size);
+        self.offset += work_size;
         // rayon::subgraph("actual sort", self.blocksize, || piece.sort());
-        piece.sort();
-        let buffer = cut_off_left(&mut self.to, work_size);
-        let merge = merge::MergeResult::new(piece, buffer);
-        self.pieces.push(merge);
-        // try merging pieces
-        self.merge_three();
-
-        return;
+        if self.leaves_in_buffer && self.load.is_none() {
+            // Sort both halves in place and merge them over, so every element only moves to the
+            // buffer once. If a comparison panics during the merge, everything goes back into
+            // the data.
+            let (left, right) = piece.split_at_mut(work_size / 2);
+            self.compare.sort_leaf(left);
+            self.compare.sort_leaf(right);
+            let move_back = MoveBack {
+                from: buffer.as_ptr(),
+                to: piece.as_mut_ptr(),
+                len: work_size,
+            };
+            let (left, right) = piece.split_at(work_size / 2);
+            let mut merge = slice_merge::SliceMerge::new(
+                left,
+                right,
+                buffer,
+                self.config.merge_work_size,
+                self.config.split_factor,
+                self.compare,
+            );
+            while !merge.is_finished() {
+                merge.step();
+            }
+            std::mem::forget(move_back);
+            std::mem::swap(&mut piece, &mut buffer);
+        } else if !natural {
+            // in natural mode the run is sorted already
+            self.compare.sort_leaf(piece);
+        }
+        let mut merge = merge::MergeResult::new(
+            piece,
+            buffer,
+            self.config.merge_work_size,
+            self.config.split_factor,
+        );
+        merge.in_buffer = self.leaves_in_buffer;
+        merge.limit = self.config.limit;
+        self.push(merge, run);
+        if self.data.is_empty() {
+            // the last task that split off is the next one to the right
+            while let Some(mut other) = self.stolen.pop() {
+                self.fuse(&mut other);
+            }
+        }
     }
     fn is_finished(&self) -> bool {
         self.data.is_empty()
@@ -263,29 +810,51 @@ where
         // split the data in two, sort them in two tasks
         let elem_left = self.data.len();
 
-        let already_done = self.pieces_len().iter().sum::<usize>();
-        let total = already_done + elem_left;
-        let powers_of_three = [
-            243, 729, 2187, 6561, 19683, 59049, 177147, 531441, 1594323, 4782969,
-        ];
-
-        let split = powers_of_three
-            .iter()
-            .take_while(|&&x| x < elem_left)
-            .last()
-            .unwrap();
-        let right_to = cut_off_right(&mut self.to, total - split - already_done);
-        let right_data = cut_off_right(&mut self.data, total - split - already_done);
+        let split = if self.natural() {
+            // the runs don't follow a pattern anyway
+            elem_left / 2
+        } else {
+            // at a boundary with a low power in the middle half, that way we mostly end up with
+            // the same merges as if we didn't split
+            let blocksize = self.config.blocksize;
+            let from = self.offset / blocksize;
+            let to = (self.offset + elem_left).div_ceil(blocksize);
+            policy::split_block(self.len, self.config.arity, blocksize, from, to) * blocksize
+                - self.offset
+        };
+        let right_to = cut_off_right(&mut self.to, split);
+        let right_data = cut_off_right(&mut self.data, split);
 
         // Other side
-        let mut other: Mergesort<'a, T> = Mergesort {
+        let mut other: Mergesort<'a, T, F> = Mergesort {
             pieces: Vec::new(),
+            offset: self.offset + self.data.len(),
             data: right_data,
             to: right_to,
-            blocksize: self.blocksize,
+            compare: self.compare,
+            config: self.config,
+            load: self.load,
+            leaves_in_buffer: self.leaves_in_buffer,
+            runs: self.runs,
+            powers: Vec::new(),
+            first_run: None,
+            last_run: None,
+            len: self.len,
+            merging: false,
+            stolen: Vec::new(),
+        };
+        // The tasks that split off during a merge before have their data behind the new one's,
+        // so they only go on our stack once it's fused.
+        let stolen = if self.merging {
+            Vec::new()
+        } else {
+            std::mem::take(&mut self.stolen)
         };
 
-        runner(&mut vec![self, &mut other]);
+        runner(&mut vec![&mut *self, &mut other]);
+        for mut stolen in stolen.into_iter().rev() {
+            self.fuse(&mut stolen);
+        }
         return;
 
         /*
@@ -350,19 +919,42 @@ where
             */
     }
     fn can_split(&self) -> bool {
-        return self.data.len() > self.blocksize * 32;
+        return self.data.len() > self.config.blocksize * self.config.split_factor;
     }
     fn fuse(&mut self, other: &mut Self) {
-        self.merge_three();
-        // for x in other.pieces.iter_mut() {
-        //     self.pieces.push(x);
-        //     self.merge();
-        // }
-        assert!(other.pieces.len() == 1, format!("{:?}", other.pieces_len()));
-
-        self.pieces.append(&mut other.pieces);
-
-        self.merge_three();
+        if self.merging {
+            // The other task split off while we were merging and is done already, but the piece
+            // we're about to push and the rest of our data come before its pieces. They go on
+            // the stack once we're done with our data.
+            let stolen = other.take_pieces();
+            self.stolen.push(stolen);
+            return;
+        }
+        debug_assert!(self.is_finished() || self.compare.aborted());
+        if self.compare.aborted() || other.last_run.is_none() {
+            // the pieces just need to be restored
+            self.pieces.append(&mut other.pieces);
+            return;
+        }
+        // put the other task's pieces on our stack as if we had sorted them ourselves
+        let first = policy::power(
+            self.len,
+            self.config.arity,
+            self.last_run.unwrap(),
+            other.first_run.unwrap(),
+        );
+        let powers: Vec<u32> = std::iter::once(first)
+            .chain(other.powers.drain(..))
+            .collect();
+        for next in powers {
+            self.merge_down(next);
+            // only take the piece now, if a merge panics it's still there for the other task to
+            // restore
+            let piece = other.pieces.remove(0);
+            self.pieces.push(piece);
+            self.powers.push(next);
+        }
+        self.last_run = other.last_run;
     }
     fn work(&self) -> Option<(&'static str, usize)> {
         Some(("Sorting", self.data.len()))
@@ -1,55 +1,105 @@
+use crate::compare::Comparator;
 use crate::slice_merge;
 pub use adaptive_algorithms::Task;
+use std::cmp::Ordering;
 // use std::sync::atomic::AtomicUsize;
 
-const BLOCKSIZE: usize = 81;
-
 #[derive(Debug, PartialEq, Eq)]
 pub struct MergeResult<'a, T>
 where
-    T: Ord + Sync + Send + Copy,
+    T: Send,
 {
     pub data: &'a mut [T], // that's where it starts and should be after it's merged
     pub buffer: &'a mut [T], // that's where it temporarily might be
-    pub blocksize: usize,  // index in total
+    pub blocksize: usize,  // merge work size
+    pub split_factor: usize,
+    pub in_buffer: bool, // true if `data` is in the scratch buffer and `buffer` in the input
+    pub limit: usize,    // only the first `limit` elements need to end up sorted
 }
 
 impl<'a, T> MergeResult<'a, T>
 where
-    T: Ord + Sync + Send + Copy,
+    T: Send,
 {
-    pub fn new(data: &'a mut [T], buffer: &'a mut [T]) -> MergeResult<'a, T> {
+    pub fn new(
+        data: &'a mut [T],
+        buffer: &'a mut [T],
+        blocksize: usize,
+        split_factor: usize,
+    ) -> MergeResult<'a, T> {
         assert_eq!(data.len(), buffer.len());
         MergeResult {
             data,
             buffer,
-            blocksize: BLOCKSIZE,
+            blocksize,
+            split_factor,
+            in_buffer: false,
+            limit: usize::MAX,
+        }
+    }
+    /// Moves the elements back into the input if they are in the scratch buffer.
+    pub fn restore(self: &mut Self) {
+        if self.in_buffer {
+            self.switch_sides();
+        }
+    }
+    /// Moves the elements from the input to the scratch buffer or the other way around.
+    pub fn switch_sides(self: &mut Self) {
+        unsafe {
+            std::ptr::copy_nonoverlapping(
+                self.data.as_ptr(),
+                self.buffer.as_mut_ptr(),
+                self.data.len(),
+            );
         }
+        std::mem::swap(&mut self.data, &mut self.buffer);
+        self.in_buffer = !self.in_buffer;
     }
     pub fn len(self: &Self) -> usize {
         return self.data.len();
     }
-    pub fn is_sorted(self: &Self) -> bool {
-        self.data.windows(2).all(|w| w[0] <= w[1])
+    /// Checks the first `limit` elements, the rest may be in any order.
+    pub fn is_sorted_by<F>(self: &Self, compare: &F) -> bool
+    where
+        F: Comparator<T>,
+    {
+        let sorted = std::cmp::min(self.limit, self.data.len());
+        self.data[..sorted]
+            .windows(2)
+            .all(|w| compare.compare(&w[0], &w[1]) != Ordering::Greater)
     }
 
-    pub fn merge_with(mut self: &mut Self, other: MergeResult<T>, f: &mut impl Task) {
+    pub fn merge_with<F>(mut self: &mut Self, other: MergeResult<T>, compare: &F, f: &mut impl Task)
+    where
+        F: Comparator<T> + Sync,
+    {
         let mut buffer = fuse_slices(self.buffer, other.buffer);
-        let mut merge =
-            slice_merge::SliceMerge::new(self.data, other.data, &mut buffer, self.blocksize);
+        let mut merge = slice_merge::SliceMerge::new(
+            self.data,
+            other.data,
+            &mut buffer,
+            self.blocksize,
+            self.split_factor,
+            compare,
+        )
+        .with_limit(self.limit);
         let data = fuse_slices(self.data, other.data);
 
         self.data = buffer;
         self.buffer = data;
+        self.in_buffer = !self.in_buffer;
 
         merge.run_with(f)
     }
-    pub fn merge_three(
+    pub fn merge_three<F>(
         mut self: &mut Self,
         other: MergeResult<T>,
         other2: MergeResult<T>,
+        compare: &F,
         f: &mut impl Task,
-    ) {
+    ) where
+        F: Comparator<T> + Sync,
+    {
         let mut buffer = fuse_slices(self.buffer, other.buffer);
         let mut buffer = fuse_slices(buffer, other2.buffer);
         let mut merge = crate::three_merge::ThreeMerge::new(
@@ -58,28 +108,110 @@ where
             other2.data,
             &mut buffer,
             self.blocksize,
-        );
+            self.split_factor,
+            compare,
+        )
+        .with_limit(self.limit);
         let data = fuse_slices(self.data, other.data);
         let data = fuse_slices(data, other2.data);
 
         self.data = buffer;
         self.buffer = data;
+        self.in_buffer = !self.in_buffer;
+
+        merge.run_with(f)
+    }
+    /// Merges any number of following pieces into this one with a loser tree. The result goes to
+    /// the scratch buffer if `to_buffer`, and the pieces that are on that side already get read
+    /// where they are instead of being moved out of the way first.
+    pub fn merge_many<F>(
+        self: &mut Self,
+        others: Vec<MergeResult<'a, T>>,
+        to_buffer: bool,
+        compare: &F,
+        f: &mut impl Task,
+    ) where
+        F: Comparator<T> + Sync,
+    {
+        let pieces = std::iter::once((
+            std::mem::take(&mut self.data),
+            std::mem::take(&mut self.buffer),
+            self.in_buffer,
+        ))
+        .chain(
+            others
+                .into_iter()
+                .map(|other| (other.data, other.buffer, other.in_buffer)),
+        );
+        // the output, and the other side of it
+        let mut output: &mut [T] = &mut [];
+        let mut spare: &mut [T] = &mut [];
+        let mut inputs: Vec<&[T]> = Vec::new();
+        for (data, buffer, in_buffer) in pieces {
+            inputs.push(unsafe { std::slice::from_raw_parts(data.as_ptr(), data.len()) });
+            let (out, other) = if in_buffer == to_buffer {
+                (data, buffer)
+            } else {
+                (buffer, data)
+            };
+            if output.is_empty() {
+                output = out;
+                spare = other;
+            } else {
+                output = fuse_slices(output, out);
+                spare = fuse_slices(spare, other);
+            }
+        }
+        let offset = spare.as_ptr() as isize - output.as_ptr() as isize;
+        let mut merge = crate::k_merge::KMerge::new(
+            &inputs,
+            output,
+            self.blocksize,
+            self.split_factor,
+            compare,
+        )
+        .with_spare(offset)
+        .with_limit(self.limit);
+
+        self.data = output;
+        self.buffer = spare;
+        self.in_buffer = to_buffer;
 
         merge.run_with(f)
     }
-    pub fn merge(mut self: &mut Self, other: MergeResult<T>) {
+    pub fn merge<F>(mut self: &mut Self, other: MergeResult<T>, compare: &F)
+    where
+        F: Comparator<T> + Sync,
+    {
         let mut buffer = fuse_slices(self.buffer, other.buffer);
-        let mut merge =
-            slice_merge::SliceMerge::new(self.data, other.data, &mut buffer, self.blocksize);
+        let mut merge = slice_merge::SliceMerge::new(
+            self.data,
+            other.data,
+            &mut buffer,
+            self.blocksize,
+            self.split_factor,
+            compare,
+        )
+        .with_limit(self.limit);
         let data = fuse_slices(self.data, other.data);
 
         self.data = buffer;
         self.buffer = data;
+        self.in_buffer = !self.in_buffer;
 
         merge.run()
     }
 }
 
+impl<'a, T> MergeResult<'a, T>
+where
+    T: Ord + Send,
+{
+    pub fn is_sorted(self: &Self) -> bool {
+        self.is_sorted_by(&|a: &T, b: &T| a.cmp(b))
+    }
+}
+
 pub fn fuse_slices<'a, 'b, 'c: 'a + 'b, T: 'c>(s1: &'a mut [T], s2: &'b mut [T]) -> &'c mut [T] {
     let ptr1 = s1.as_mut_ptr();
     unsafe {
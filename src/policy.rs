@@ -0,0 +1,173 @@
+//! When to merge which pieces. It's powersort, but with a merge tree of any arity: every boundary
+//! between two runs gets the depth of the node in a perfectly balanced tree over the whole input
+//! where the two runs meet. Boundaries deeper than the next one get merged first, boundaries of
+//! the same depth next to each other belong to the same node and get merged together. With arity
+//! 2 that's plain powersort, with arity 3 a node can make a three-way merge, and so on.
+
+/// The power of the boundary between the runs `a` and `b` (start and end positions) in an input of
+/// `n` elements, in a tree of the given arity.
+pub(crate) fn power(n: usize, arity: usize, a: (usize, usize), b: (usize, usize)) -> u32 {
+    // twice the midpoints, so it all stays integer
+    let n = 2 * n as u128;
+    let (a, b) = ((a.0 + a.1) as u128, (b.0 + b.1) as u128);
+    let mut power = 1;
+    let mut scale = arity as u128;
+    // we're done once the scaled midpoints are at least n apart, long before this overflows
+    while a * scale / n == b * scale / n {
+        power += 1;
+        scale *= arity as u128;
+    }
+    power
+}
+
+/// How many pieces to merge before the next boundary with power `next` goes on the stack.
+/// `powers` are the boundaries between the pieces on the stack. A `next` of 0 merges everything.
+pub(crate) fn next_merge(powers: &[u32], next: u32, arity: usize) -> Option<usize> {
+    let top = *powers.last()?;
+    if top <= next {
+        return None;
+    }
+    // all boundaries of the same node, but never more pieces than the arity
+    let same = powers
+        .iter()
+        .rev()
+        .take_while(|&&power| power == top)
+        .count();
+    Some(std::cmp::min(same, arity - 1) + 1)
+}
+
+/// Of the pieces with these lengths, which one stays where it is when we merge them. The result
+/// goes to the other side.
+pub(crate) fn keeps_side(lengths: &[usize]) -> usize {
+    // the longest one, the first on ties
+    let longest = *lengths.iter().max().unwrap();
+    lengths.iter().position(|&len| len == longest).unwrap()
+}
+
+/// Where to split blocks `from..to` of an input of `n` elements in blocks of `blocksize`. That's
+/// the boundary with the lowest power (closest to the middle if there are several) that leaves at
+/// least a quarter of the blocks on each side. If a task covers a whole node of the tree that's
+/// the lowest power of all, and nothing behind it ever gets merged with anything before it before
+/// the boundary itself goes on the stack, so the merges are the same no matter how we split.
+pub(crate) fn split_block(
+    n: usize,
+    arity: usize,
+    blocksize: usize,
+    from: usize,
+    to: usize,
+) -> usize {
+    assert!(to - from >= 2, "nothing to split");
+    let quarter = std::cmp::max(1, (to - from) / 4);
+    // we look at the boundaries in front of the blocks first + 1..last
+    let (first, last) = (from + quarter - 1, to - quarter + 1);
+    // twice the midpoint of each block
+    let mid = |block: usize| {
+        let start = block * blocksize;
+        (2 * start + std::cmp::min(blocksize, n - start)) as u128
+    };
+    let n2 = 2 * n as u128;
+    // cells of the tree at the depth of the scale
+    let cell = |block: usize, scale: u128| mid(block) * scale / n2;
+    let mut scale = arity as u128;
+    while cell(first, scale) == cell(last - 1, scale) {
+        scale *= arity as u128;
+    }
+    // the first block in a later cell than `c`, or `last` if there's none
+    let first_after = |c: u128| {
+        let (mut low, mut high) = (first + 1, last);
+        while low < high {
+            let m = low + (high - low) / 2;
+            if cell(m, scale) <= c {
+                low = m + 1;
+            } else {
+                high = m;
+            }
+        }
+        low
+    };
+    // the boundaries between the cells, there's at most arity - 1 of them
+    let middle = (from + to) / 2;
+    let distance = |block: usize| (block as isize - middle as isize).abs();
+    let mut best = first_after(cell(first, scale));
+    let mut next = first_after(cell(best, scale));
+    while next < last {
+        if distance(next) < distance(best) {
+            best = next;
+        }
+        next = first_after(cell(next, scale));
+    }
+    best
+}
+
+/// Simulates the merges of sorting `len` elements in blocks of `blocksize` with the leaves in the
+/// data with a merge tree of the given arity, and tells whether the result lands in the scratch
+/// buffer.
+pub(crate) fn lands_in_buffer(len: usize, arity: usize, blocksize: usize) -> bool {
+    let blocks = len.div_ceil(blocksize);
+    // (length, in buffer) of each piece
+    let mut pieces: Vec<(usize, bool)> = Vec::new();
+    let mut powers: Vec<u32> = Vec::new();
+    let run = |block: usize| {
+        (
+            block * blocksize,
+            std::cmp::min(len, (block + 1) * blocksize),
+        )
+    };
+    let merge = |pieces: &mut Vec<(usize, bool)>, count: usize| {
+        let merged = pieces.split_off(pieces.len() - count);
+        let lengths: Vec<usize> = merged.iter().map(|piece| piece.0).collect();
+        let side = merged[keeps_side(&lengths)].1;
+        // merging moves it to the other side
+        pieces.push((lengths.iter().sum(), !side));
+    };
+    for block in 0..blocks {
+        if block > 0 {
+            let next = power(len, arity, run(block - 1), run(block));
+            while let Some(count) = next_merge(&powers, next, arity) {
+                merge(&mut pieces, count);
+                powers.truncate(powers.len() + 1 - count);
+            }
+            powers.push(next);
+        }
+        pieces.push((run(block).1 - run(block).0, false));
+    }
+    // whatever is left gets merged at once
+    let count = pieces.len();
+    if count >= 2 {
+        merge(&mut pieces, count);
+    }
+    matches!(pieces.first(), Some(&(_, true)))
+}
+
+#[test]
+pub fn split_schedule() {
+    // thieves always steal from the biggest task, which might have done some of its work already
+    let blocksize = 81;
+    for &arity in &[2, 3, 4] {
+        for &n in &[
+            1_000_000,
+            3usize.pow(14) + 1,
+            1 << 32,
+            5_000_000_000,
+            1 << 40,
+        ] {
+            let mut tasks = vec![(0, n.div_ceil(blocksize))];
+            for steal in 0..63 {
+                let biggest = (0..tasks.len())
+                    .max_by_key(|&i| tasks[i].1 - tasks[i].0)
+                    .unwrap();
+                let (from, to) = tasks.swap_remove(biggest);
+                let from = from + (to - from) * (steal % 4) / 16;
+                let split = split_block(n, arity, blocksize, from, to);
+                let quarter = (to - from) / 4;
+                assert!(split - from >= quarter && to - split >= quarter);
+                tasks.push((from, split));
+                tasks.push((split, to));
+            }
+            // nobody has much more than their share of the work that's left
+            let left: usize = tasks.iter().map(|&(from, to)| to - from).sum();
+            let biggest = tasks.iter().map(|&(from, to)| to - from).max().unwrap();
+            assert!(biggest * tasks.len() <= 2 * left);
+        }
+    }
+}
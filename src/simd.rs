@@ -0,0 +1,596 @@
+//! Vectorized merges for primitive keys. The kernels work on a whole register of elements at a
+//! time with a bitonic merge network: for the next `W` outputs, only the next `W` elements of
+//! each input matter, so we load them, keep the smallest `W` with a network of min/max
+//! operations, store them and count how many came from where. Equal keys are equal bit for bit,
+//! so it doesn't matter which copy ends up where, and the output is exactly the one of the
+//! scalar merge.
+//! On x86_64 the kernel is picked at runtime (AVX2, then SSE4.2), everywhere else and on older
+//! CPUs the scalar merge does all the work.
+use crate::compare::Comparator;
+use crate::slice_merge::merge_into;
+use crate::{mergesort_with, MergesortConfig};
+use std::cmp::Ordering;
+
+mod private {
+    pub trait Sealed {}
+}
+
+/// The primitive types `mergesort_keys` and `par_merge_keys` can merge with SIMD instructions:
+/// `u32`, `i32`, `f32`, `u64`, `i64` and `f64`.
+/// Floats are ordered by the IEEE 754 total order, so `-0.0` comes before `0.0`, and NaNs go to
+/// the ends depending on their sign.
+pub trait Key: Copy + Send + Sync + private::Sealed {
+    /// The order the keys get sorted in.
+    fn key_cmp(&self, other: &Self) -> Ordering;
+    #[doc(hidden)]
+    unsafe fn merge_blocks(inputs: &mut [(*const Self, *const Self)], output: &mut *mut Self);
+}
+
+/// Sorts `data` like `mergesort`, with the vectorized merges where the CPU has them.
+pub fn mergesort_keys<T: Key>(data: &mut [T]) {
+    mergesort_with(data, &KeyOrder, &MergesortConfig::default())
+}
+
+/// Merges the sorted `left` and `right` into `out` like `par_merge`, with the vectorized merges
+/// where the CPU has them.
+pub fn par_merge_keys<T: Key>(left: &[T], right: &[T], out: &mut [T]) {
+    merge_into(left, right, out, &KeyOrder)
+}
+
+/// Compares keys with `Key::key_cmp`, and merges them with the kernels.
+struct KeyOrder;
+
+impl<T: Key> Comparator<T> for KeyOrder {
+    fn compare(&self, a: &T, b: &T) -> Ordering {
+        a.key_cmp(b)
+    }
+    fn sort_leaf(&self, piece: &mut [T]) {
+        // equal keys can't be told apart, so stability doesn't matter
+        piece.sort_unstable_by(|a, b| a.key_cmp(b))
+    }
+    unsafe fn merge_blocks(&self, inputs: &mut [(*const T, *const T)], output: &mut *mut T) {
+        T::merge_blocks(inputs, output)
+    }
+}
+
+/// How the bits of a key map to a signed integer with the same order. The mappings are their own
+/// inverse, so the kernels use them on the way in and on the way out.
+#[derive(Clone, Copy)]
+enum Order {
+    Signed,
+    Unsigned,
+    Float, // flip everything but the sign of negative numbers (like f32::total_cmp)
+}
+
+fn key32(bits: u32, order: Order) -> i32 {
+    match order {
+        Order::Signed => bits as i32,
+        Order::Unsigned => (bits ^ (1 << 31)) as i32,
+        Order::Float => (bits ^ (((bits as i32 >> 31) as u32) >> 1)) as i32,
+    }
+}
+
+fn key64(bits: u64, order: Order) -> i64 {
+    match order {
+        Order::Signed => bits as i64,
+        Order::Unsigned => (bits ^ (1 << 63)) as i64,
+        Order::Float => (bits ^ (((bits as i64 >> 63) as u64) >> 1)) as i64,
+    }
+}
+
+unsafe fn merge32(inputs: &mut [(*const u32, *const u32)], output: &mut *mut u32, order: Order) {
+    #[cfg(target_arch = "x86_64")]
+    {
+        if is_x86_feature_detected!("avx2") {
+            return x86::merge_avx2_32(inputs, output, order);
+        }
+        if is_x86_feature_detected!("sse4.2") {
+            return x86::merge_sse42_32(inputs, output, order);
+        }
+    }
+    // no kernel here, the scalar merge does it all
+    let _ = (inputs, output, order);
+}
+
+unsafe fn merge64(inputs: &mut [(*const u64, *const u64)], output: &mut *mut u64, order: Order) {
+    #[cfg(target_arch = "x86_64")]
+    {
+        if is_x86_feature_detected!("avx2") {
+            return x86::merge_avx2_64(inputs, output, order);
+        }
+        if is_x86_feature_detected!("sse4.2") {
+            return x86::merge_sse42_64(inputs, output, order);
+        }
+    }
+    let _ = (inputs, output, order);
+}
+
+macro_rules! impl_key {
+    ($t:ty, $bits:ty, $key:ident, $merge:ident, $order:expr) => {
+        impl private::Sealed for $t {}
+        impl Key for $t {
+            fn key_cmp(&self, other: &Self) -> Ordering {
+                let bits = |x: &Self| unsafe { *(x as *const Self as *const $bits) };
+                $key(bits(self), $order).cmp(&$key(bits(other), $order))
+            }
+            unsafe fn merge_blocks(
+                inputs: &mut [(*const Self, *const Self)],
+                output: &mut *mut Self,
+            ) {
+                $merge(
+                    &mut *(inputs as *mut [(*const Self, *const Self)]
+                        as *mut [(*const $bits, *const $bits)]),
+                    &mut *(output as *mut *mut Self as *mut *mut $bits),
+                    $order,
+                )
+            }
+        }
+    };
+}
+
+impl_key!(u32, u32, key32, merge32, Order::Unsigned);
+impl_key!(i32, u32, key32, merge32, Order::Signed);
+impl_key!(f32, u32, key32, merge32, Order::Float);
+impl_key!(u64, u64, key64, merge64, Order::Unsigned);
+impl_key!(i64, u64, key64, merge64, Order::Signed);
+impl_key!(f64, u64, key64, merge64, Order::Float);
+
+#[cfg(target_arch = "x86_64")]
+mod x86 {
+    use super::Order;
+    use std::arch::x86_64::*;
+
+    /// A register full of keys, with the operations the merge network needs. All comparisons are
+    /// signed, the keys get mapped with `flip` first.
+    trait Lanes: Copy {
+        type Elem;
+        const WIDTH: usize;
+        unsafe fn load(ptr: *const Self::Elem) -> Self;
+        unsafe fn store(self, ptr: *mut Self::Elem);
+        unsafe fn flip(self, order: Order) -> Self;
+        unsafe fn min(self, other: Self) -> Self;
+        unsafe fn reverse(self) -> Self;
+        /// Sorts a register that goes up and then down.
+        unsafe fn sort_bitonic(self) -> Self;
+        /// The last lane in every lane.
+        unsafe fn broadcast_last(self) -> Self;
+        unsafe fn count_less(self, than: Self) -> usize;
+        unsafe fn count_equal(self, to: Self) -> usize;
+    }
+
+    /// Merges blocks of `WIDTH` elements while every input has more than that left.
+    #[inline(always)]
+    unsafe fn merge_blocks<V: Lanes>(
+        inputs: &mut [(*const V::Elem, *const V::Elem)],
+        output: &mut *mut V::Elem,
+        order: Order,
+    ) {
+        // so that the mapping gets compiled into the loop
+        match order {
+            Order::Signed => merge_flipped::<V>(inputs, output, |v| v),
+            Order::Unsigned => merge_flipped::<V>(inputs, output, |v| v.flip(Order::Unsigned)),
+            Order::Float => merge_flipped::<V>(inputs, output, |v| v.flip(Order::Float)),
+        }
+    }
+
+    #[inline(always)]
+    unsafe fn merge_flipped<V: Lanes>(
+        inputs: &mut [(*const V::Elem, *const V::Elem)],
+        output: &mut *mut V::Elem,
+        flip: impl Fn(V) -> V,
+    ) {
+        let width = V::WIDTH;
+        let more = |start: *const V::Elem, end: *const V::Elem| {
+            end as usize - start as usize > width * std::mem::size_of::<V::Elem>()
+        };
+        match inputs {
+            [(a, a_end), (b, b_end)] => {
+                while more(*a, *a_end) && more(*b, *b_end) {
+                    let block_a = flip(V::load(*a));
+                    let block_b = flip(V::load(*b)).reverse();
+                    // `block_a` goes up and `block_b` down, so the smaller `width` are a prefix of
+                    // `a` and one of `b`, and the lanes where `b` is smaller say how long
+                    let from_b = block_b.count_less(block_a);
+                    // the right input of a SliceMerge may be in the output, it's loaded by now
+                    flip(block_a.min(block_b).sort_bitonic()).store(*output);
+                    *output = output.add(width);
+                    *a = a.add(width - from_b);
+                    *b = b.add(from_b);
+                }
+            }
+            [(a, a_end), (b, b_end), (c, c_end)] => {
+                while more(*a, *a_end) && more(*b, *b_end) && more(*c, *c_end) {
+                    let block_a = flip(V::load(*a));
+                    let block_b = flip(V::load(*b));
+                    let block_c = flip(V::load(*c));
+                    let lowest = block_a.min(block_b.reverse()).sort_bitonic();
+                    let lowest = lowest.min(block_c.reverse()).sort_bitonic();
+                    // every block gives the elements below the largest one we keep, and ties go
+                    // to the first blocks, as many as there is room for
+                    let last = lowest.broadcast_last();
+                    let less = [
+                        block_a.count_less(last),
+                        block_b.count_less(last),
+                        block_c.count_less(last),
+                    ];
+                    let room = width - less[0] - less[1] - less[2];
+                    let from_a = less[0] + std::cmp::min(room, block_a.count_equal(last));
+                    let room = width - from_a - less[1] - less[2];
+                    let from_b = less[1] + std::cmp::min(room, block_b.count_equal(last));
+                    flip(lowest).store(*output);
+                    *output = output.add(width);
+                    *a = a.add(from_a);
+                    *b = b.add(from_b);
+                    *c = c.add(width - from_a - from_b);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    #[target_feature(enable = "avx2")]
+    pub(super) unsafe fn merge_avx2_32(
+        inputs: &mut [(*const u32, *const u32)],
+        output: &mut *mut u32,
+        order: Order,
+    ) {
+        merge_blocks::<Avx2x32>(inputs, output, order)
+    }
+
+    #[target_feature(enable = "avx2")]
+    pub(super) unsafe fn merge_avx2_64(
+        inputs: &mut [(*const u64, *const u64)],
+        output: &mut *mut u64,
+        order: Order,
+    ) {
+        merge_blocks::<Avx2x64>(inputs, output, order)
+    }
+
+    #[target_feature(enable = "sse4.2")]
+    pub(super) unsafe fn merge_sse42_32(
+        inputs: &mut [(*const u32, *const u32)],
+        output: &mut *mut u32,
+        order: Order,
+    ) {
+        merge_blocks::<Sse4x32>(inputs, output, order)
+    }
+
+    #[target_feature(enable = "sse4.2")]
+    pub(super) unsafe fn merge_sse42_64(
+        inputs: &mut [(*const u64, *const u64)],
+        output: &mut *mut u64,
+        order: Order,
+    ) {
+        merge_blocks::<Sse4x64>(inputs, output, order)
+    }
+
+    #[derive(Clone, Copy)]
+    struct Avx2x32(__m256i);
+
+    impl Lanes for Avx2x32 {
+        type Elem = u32;
+        const WIDTH: usize = 8;
+        #[inline(always)]
+        unsafe fn load(ptr: *const u32) -> Self {
+            Avx2x32(_mm256_loadu_si256(ptr as *const __m256i))
+        }
+        #[inline(always)]
+        unsafe fn store(self, ptr: *mut u32) {
+            _mm256_storeu_si256(ptr as *mut __m256i, self.0)
+        }
+        #[inline(always)]
+        unsafe fn flip(self, order: Order) -> Self {
+            Avx2x32(match order {
+                Order::Signed => self.0,
+                Order::Unsigned => _mm256_xor_si256(self.0, _mm256_set1_epi32(i32::MIN)),
+                Order::Float => {
+                    _mm256_xor_si256(self.0, _mm256_srli_epi32(_mm256_srai_epi32(self.0, 31), 1))
+                }
+            })
+        }
+        #[inline(always)]
+        unsafe fn min(self, other: Self) -> Self {
+            Avx2x32(_mm256_min_epi32(self.0, other.0))
+        }
+        #[inline(always)]
+        unsafe fn reverse(self) -> Self {
+            Avx2x32(_mm256_permutevar8x32_epi32(
+                self.0,
+                _mm256_setr_epi32(7, 6, 5, 4, 3, 2, 1, 0),
+            ))
+        }
+        #[inline(always)]
+        unsafe fn sort_bitonic(self) -> Self {
+            // compare lanes 4 apart, then 2, then 1
+            let v = self.0;
+            let t = _mm256_permute2x128_si256(v, v, 1);
+            let v = _mm256_blend_epi32(_mm256_min_epi32(v, t), _mm256_max_epi32(v, t), 0b1111_0000);
+            let t = _mm256_shuffle_epi32(v, 0b01_00_11_10);
+            let v = _mm256_blend_epi32(_mm256_min_epi32(v, t), _mm256_max_epi32(v, t), 0b1100_1100);
+            let t = _mm256_shuffle_epi32(v, 0b10_11_00_01);
+            let v = _mm256_blend_epi32(_mm256_min_epi32(v, t), _mm256_max_epi32(v, t), 0b1010_1010);
+            Avx2x32(v)
+        }
+        #[inline(always)]
+        unsafe fn broadcast_last(self) -> Self {
+            Avx2x32(_mm256_permutevar8x32_epi32(self.0, _mm256_set1_epi32(7)))
+        }
+        #[inline(always)]
+        unsafe fn count_less(self, than: Self) -> usize {
+            let mask = _mm256_cmpgt_epi32(than.0, self.0);
+            _mm256_movemask_ps(_mm256_castsi256_ps(mask)).count_ones() as usize
+        }
+        #[inline(always)]
+        unsafe fn count_equal(self, to: Self) -> usize {
+            let mask = _mm256_cmpeq_epi32(to.0, self.0);
+            _mm256_movemask_ps(_mm256_castsi256_ps(mask)).count_ones() as usize
+        }
+    }
+
+    #[derive(Clone, Copy)]
+    struct Avx2x64(__m256i);
+
+    impl Avx2x64 {
+        #[inline(always)]
+        unsafe fn min_max(a: __m256i, b: __m256i) -> (__m256i, __m256i) {
+            // there is no 64 bit min before AVX-512
+            let greater = _mm256_cmpgt_epi64(a, b);
+            (
+                _mm256_blendv_epi8(a, b, greater),
+                _mm256_blendv_epi8(b, a, greater),
+            )
+        }
+    }
+
+    impl Lanes for Avx2x64 {
+        type Elem = u64;
+        const WIDTH: usize = 4;
+        #[inline(always)]
+        unsafe fn load(ptr: *const u64) -> Self {
+            Avx2x64(_mm256_loadu_si256(ptr as *const __m256i))
+        }
+        #[inline(always)]
+        unsafe fn store(self, ptr: *mut u64) {
+            _mm256_storeu_si256(ptr as *mut __m256i, self.0)
+        }
+        #[inline(always)]
+        unsafe fn flip(self, order: Order) -> Self {
+            Avx2x64(match order {
+                Order::Signed => self.0,
+                Order::Unsigned => _mm256_xor_si256(self.0, _mm256_set1_epi64x(i64::MIN)),
+                Order::Float => {
+                    let negative = _mm256_cmpgt_epi64(_mm256_setzero_si256(), self.0);
+                    _mm256_xor_si256(self.0, _mm256_srli_epi64(negative, 1))
+                }
+            })
+        }
+        #[inline(always)]
+        unsafe fn min(self, other: Self) -> Self {
+            Avx2x64(Self::min_max(self.0, other.0).0)
+        }
+        #[inline(always)]
+        unsafe fn reverse(self) -> Self {
+            Avx2x64(_mm256_permute4x64_epi64(self.0, 0b00_01_10_11))
+        }
+        #[inline(always)]
+        unsafe fn sort_bitonic(self) -> Self {
+            // compare lanes 2 apart, then 1
+            let v = self.0;
+            let (low, high) = Self::min_max(v, _mm256_permute4x64_epi64(v, 0b01_00_11_10));
+            let v = _mm256_blend_epi32(low, high, 0b1111_0000);
+            let (low, high) = Self::min_max(v, _mm256_shuffle_epi32(v, 0b01_00_11_10));
+            Avx2x64(_mm256_blend_epi32(low, high, 0b1100_1100))
+        }
+        #[inline(always)]
+        unsafe fn broadcast_last(self) -> Self {
+            Avx2x64(_mm256_permute4x64_epi64(self.0, 0b11_11_11_11))
+        }
+        #[inline(always)]
+        unsafe fn count_less(self, than: Self) -> usize {
+            let mask = _mm256_cmpgt_epi64(than.0, self.0);
+            _mm256_movemask_pd(_mm256_castsi256_pd(mask)).count_ones() as usize
+        }
+        #[inline(always)]
+        unsafe fn count_equal(self, to: Self) -> usize {
+            let mask = _mm256_cmpeq_epi64(to.0, self.0);
+            _mm256_movemask_pd(_mm256_castsi256_pd(mask)).count_ones() as usize
+        }
+    }
+
+    #[derive(Clone, Copy)]
+    struct Sse4x32(__m128i);
+
+    impl Lanes for Sse4x32 {
+        type Elem = u32;
+        const WIDTH: usize = 4;
+        #[inline(always)]
+        unsafe fn load(ptr: *const u32) -> Self {
+            Sse4x32(_mm_loadu_si128(ptr as *const __m128i))
+        }
+        #[inline(always)]
+        unsafe fn store(self, ptr: *mut u32) {
+            _mm_storeu_si128(ptr as *mut __m128i, self.0)
+        }
+        #[inline(always)]
+        unsafe fn flip(self, order: Order) -> Self {
+            Sse4x32(match order {
+                Order::Signed => self.0,
+                Order::Unsigned => _mm_xor_si128(self.0, _mm_set1_epi32(i32::MIN)),
+                Order::Float => {
+                    _mm_xor_si128(self.0, _mm_srli_epi32(_mm_srai_epi32(self.0, 31), 1))
+                }
+            })
+        }
+        #[inline(always)]
+        unsafe fn min(self, other: Self) -> Self {
+            Sse4x32(_mm_min_epi32(self.0, other.0))
+        }
+        #[inline(always)]
+        unsafe fn reverse(self) -> Self {
+            Sse4x32(_mm_shuffle_epi32(self.0, 0b00_01_10_11))
+        }
+        #[inline(always)]
+        unsafe fn sort_bitonic(self) -> Self {
+            // compare lanes 2 apart, then 1
+            let v = self.0;
+            let t = _mm_shuffle_epi32(v, 0b01_00_11_10);
+            let v = _mm_blend_epi16(_mm_min_epi32(v, t), _mm_max_epi32(v, t), 0b1111_0000);
+            let t = _mm_shuffle_epi32(v, 0b10_11_00_01);
+            let v = _mm_blend_epi16(_mm_min_epi32(v, t), _mm_max_epi32(v, t), 0b1100_1100);
+            Sse4x32(v)
+        }
+        #[inline(always)]
+        unsafe fn broadcast_last(self) -> Self {
+            Sse4x32(_mm_shuffle_epi32(self.0, 0b11_11_11_11))
+        }
+        #[inline(always)]
+        unsafe fn count_less(self, than: Self) -> usize {
+            let mask = _mm_cmpgt_epi32(than.0, self.0);
+            _mm_movemask_ps(_mm_castsi128_ps(mask)).count_ones() as usize
+        }
+        #[inline(always)]
+        unsafe fn count_equal(self, to: Self) -> usize {
+            let mask = _mm_cmpeq_epi32(to.0, self.0);
+            _mm_movemask_ps(_mm_castsi128_ps(mask)).count_ones() as usize
+        }
+    }
+
+    #[derive(Clone, Copy)]
+    struct Sse4x64(__m128i);
+
+    impl Sse4x64 {
+        #[inline(always)]
+        unsafe fn min_max(a: __m128i, b: __m128i) -> (__m128i, __m128i) {
+            let greater = _mm_cmpgt_epi64(a, b);
+            (
+                _mm_blendv_epi8(a, b, greater),
+                _mm_blendv_epi8(b, a, greater),
+            )
+        }
+    }
+
+    impl Lanes for Sse4x64 {
+        type Elem = u64;
+        const WIDTH: usize = 2;
+        #[inline(always)]
+        unsafe fn load(ptr: *const u64) -> Self {
+            Sse4x64(_mm_loadu_si128(ptr as *const __m128i))
+        }
+        #[inline(always)]
+        unsafe fn store(self, ptr: *mut u64) {
+            _mm_storeu_si128(ptr as *mut __m128i, self.0)
+        }
+        #[inline(always)]
+        unsafe fn flip(self, order: Order) -> Self {
+            Sse4x64(match order {
+                Order::Signed => self.0,
+                Order::Unsigned => _mm_xor_si128(self.0, _mm_set1_epi64x(i64::MIN)),
+                Order::Float => {
+                    let negative = _mm_cmpgt_epi64(_mm_setzero_si128(), self.0);
+                    _mm_xor_si128(self.0, _mm_srli_epi64(negative, 1))
+                }
+            })
+        }
+        #[inline(always)]
+        unsafe fn min(self, other: Self) -> Self {
+            Sse4x64(Self::min_max(self.0, other.0).0)
+        }
+        #[inline(always)]
+        unsafe fn reverse(self) -> Self {
+            Sse4x64(_mm_shuffle_epi32(self.0, 0b01_00_11_10))
+        }
+        #[inline(always)]
+        unsafe fn sort_bitonic(self) -> Self {
+            let (low, high) = Self::min_max(self.0, self.reverse().0);
+            Sse4x64(_mm_blend_epi16(low, high, 0b1111_0000))
+        }
+        #[inline(always)]
+        unsafe fn broadcast_last(self) -> Self {
+            Sse4x64(_mm_shuffle_epi32(self.0, 0b11_10_11_10))
+        }
+        #[inline(always)]
+        unsafe fn count_less(self, than: Self) -> usize {
+            let mask = _mm_cmpgt_epi64(than.0, self.0);
+            _mm_movemask_pd(_mm_castsi128_pd(mask)).count_ones() as usize
+        }
+        #[inline(always)]
+        unsafe fn count_equal(self, to: Self) -> usize {
+            let mask = _mm_cmpeq_epi64(to.0, self.0);
+            _mm_movemask_pd(_mm_castsi128_pd(mask)).count_ones() as usize
+        }
+    }
+}
+
+// the sorts only use the best kernel the CPU has, so each one gets called directly
+#[cfg(target_arch = "x86_64")]
+#[test]
+pub fn sse_kernels() {
+    if is_x86_feature_detected!("sse4.2") {
+        check_kernels(x86::merge_sse42_32, x86::merge_sse42_64);
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+#[test]
+pub fn avx2_kernels() {
+    if is_x86_feature_detected!("avx2") {
+        check_kernels(x86::merge_avx2_32, x86::merge_avx2_64);
+    }
+}
+
+#[cfg(all(test, target_arch = "x86_64"))]
+type Kernel<B> = unsafe fn(&mut [(*const B, *const B)], &mut *mut B, Order);
+
+// merges random inputs with the kernels and compares with a sort
+#[cfg(all(test, target_arch = "x86_64"))]
+fn check_kernels(merge32: Kernel<u32>, merge64: Kernel<u64>) {
+    fn check<B: Copy + Eq + std::fmt::Debug>(
+        kernel: Kernel<B>,
+        key: impl Fn(B, Order) -> i64,
+        random: impl Fn() -> B,
+    ) {
+        for &order in &[Order::Signed, Order::Unsigned, Order::Float] {
+            for count in 2..=3 {
+                for _ in 0..100 {
+                    let len = || rand::random::<usize>() % 40;
+                    let mut inputs: Vec<Vec<B>> = (0..count)
+                        .map(|_| (0..len()).map(|_| random()).collect())
+                        .collect();
+                    for input in inputs.iter_mut() {
+                        input.sort_by_key(|&x| key(x, order));
+                    }
+                    let mut expected = inputs.concat();
+                    expected.sort_by_key(|&x| key(x, order));
+                    let mut out = expected.clone();
+                    let mut ranges: Vec<_> = inputs
+                        .iter()
+                        .map(|input| (input.as_ptr(), unsafe { input.as_ptr().add(input.len()) }))
+                        .collect();
+                    let mut output = out.as_mut_ptr();
+                    unsafe { kernel(&mut ranges, &mut output, order) };
+                    // the merged part is right, and what's left over is what's missing
+                    let size = std::mem::size_of::<B>();
+                    let merged = (output as usize - out.as_ptr() as usize) / size;
+                    let mut rest: Vec<B> = Vec::new();
+                    for (input, &(start, _)) in inputs.iter().zip(ranges.iter()) {
+                        let taken = (start as usize - input.as_ptr() as usize) / size;
+                        assert!(taken < input.len() || input.is_empty());
+                        rest.extend_from_slice(&input[taken..]);
+                    }
+                    rest.sort_by_key(|&x| key(x, order));
+                    assert_eq!(out[..merged], expected[..merged]);
+                    assert_eq!(rest[..], expected[merged..]);
+                }
+            }
+        }
+    }
+    // few different keys, among them NaNs and zeros of both signs for the floats
+    let few =
+        || [0, 1 << 31, 1, 0x7fc0_0000, 0xffc0_0000, 0x8000_0001][rand::random::<usize>() % 6];
+    let key = |x, order| key32(x, order) as i64;
+    check(merge32, key, few);
+    check(merge32, key, rand::random);
+    let few = || (few() as u64) << 32;
+    check(merge64, key64, few);
+    check(merge64, key64, rand::random);
+}
@@ -1,10 +1,81 @@
+use crate::compare::Comparator;
+use crate::MergesortConfig;
 use adaptive_algorithms::Task;
+use std::cmp::Ordering;
 use std::mem;
 use std::ptr;
 
-pub struct SliceMerge<T>
+/// Merges the sorted slices `left` and `right` into `out`, which must be exactly as long as both
+/// together. The merge is stable: of equal elements, the ones from `left` come first.
+pub fn par_merge<T>(left: &[T], right: &[T], out: &mut [T])
 where
-    T: Copy + Ord,
+    T: Ord + Copy + Send + Sync,
+{
+    par_merge_by(left, right, out, |a, b| a.cmp(b))
+}
+
+/// Like `par_merge`, with a comparator function.
+/// Inside a thread pool the merge gets split adaptively, otherwise it runs on the calling thread.
+pub fn par_merge_by<T, F>(left: &[T], right: &[T], out: &mut [T], compare: F)
+where
+    T: Copy + Send + Sync,
+    F: Fn(&T, &T) -> Ordering + Sync,
+{
+    merge_into(left, right, out, &compare)
+}
+
+/// `par_merge_by` for any comparator.
+pub(crate) fn merge_into<T, F>(left: &[T], right: &[T], out: &mut [T], compare: &F)
+where
+    T: Copy + Send + Sync,
+    F: Comparator<T> + Sync,
+{
+    assert_eq!(
+        left.len() + right.len(),
+        out.len(),
+        "the output needs room for both inputs"
+    );
+    let config = MergesortConfig::default();
+    // elements get copied bitwise, that's fine since they're Copy
+    let mut merge = SliceMerge::new(
+        left,
+        right,
+        out,
+        config.merge_work_size,
+        config.split_factor,
+        compare,
+    );
+    crate::run_anywhere(&mut merge);
+}
+
+/// `par_merge` with the kernel that branches on every comparison, even for elements that would get
+/// the branchless one. It's only there to benchmark the two on the same input.
+#[doc(hidden)]
+pub fn par_merge_with_branches<T>(left: &[T], right: &[T], out: &mut [T])
+where
+    T: Ord + Copy + Send + Sync,
+{
+    let config = MergesortConfig::default();
+    let compare = |a: &T, b: &T| a.cmp(b);
+    // see merge_into
+    let mut merge = SliceMerge::new(
+        left,
+        right,
+        out,
+        config.merge_work_size,
+        config.split_factor,
+        &compare,
+    );
+    merge.branchless = false;
+    crate::run_anywhere(&mut merge);
+}
+
+/// Merges two sorted inputs into the output. The right input may also sit at the back of the
+/// output, right behind room for the left one (that's how the half buffer mode merges, see
+/// `half_copy`). The output never catches up with it, so that works just as well.
+pub struct SliceMerge<'f, T, F>
+where
+    F: Comparator<T>,
 {
     pub left: *const T,
     pub left_end: *const T,
@@ -12,15 +83,31 @@ where
     pub right_end: *const T,
     pub output: *mut T,
     pub output_end: *const T,
+    pub output_limit: *const T, // everything from here on may stay unmerged
     pub work_size: usize,
+    pub split_factor: usize, // only split with more than split_factor * work_size work left
+    pub branchless: bool,    // see merge_branchless
+    pub compare: &'f F,
+}
+unsafe impl<'f, T, F> Send for SliceMerge<'f, T, F>
+where
+    T: Send,
+    F: Comparator<T> + Sync,
+{
 }
-unsafe impl<T> Send for SliceMerge<T> where T: Copy + Ord {}
 // unsafe impl<T> Sync for SliceMerge<T> where T: Copy + Ord {}
-impl<T> SliceMerge<T>
+impl<'f, T, F> SliceMerge<'f, T, F>
 where
-    T: Copy + Ord,
+    F: Comparator<T>,
 {
-    pub fn new(left: &[T], right: &[T], output: &mut [T], work_size: usize) -> SliceMerge<T> {
+    pub fn new(
+        left: &[T],
+        right: &[T],
+        output: &mut [T],
+        work_size: usize,
+        split_factor: usize,
+        compare: &'f F,
+    ) -> SliceMerge<'f, T, F> {
         assert!(left.len() + right.len() == output.len());
         unsafe {
             return SliceMerge {
@@ -30,58 +117,176 @@ where
                 right_end: right.as_ptr().add(right.len()),
                 output: output.as_mut_ptr(),
                 output_end: output.as_ptr().add(output.len()),
+                output_limit: output.as_ptr().add(output.len()),
                 work_size,
+                split_factor,
+                branchless: branchless::<T>(),
+                compare,
             };
         }
     }
+    /// Merges `left` with the `right.len()` elements at the back of `output`, which needs room for
+    /// `left` in front of them.
+    pub fn half_copy(
+        left: &[T],
+        output: &mut [T],
+        work_size: usize,
+        split_factor: usize,
+        compare: &'f F,
+    ) -> SliceMerge<'f, T, F> {
+        assert!(left.len() <= output.len());
+        unsafe {
+            let right = std::slice::from_raw_parts(
+                output.as_ptr().add(left.len()),
+                output.len() - left.len(),
+            );
+            SliceMerge::new(left, right, output, work_size, split_factor, compare)
+        }
+    }
+    /// Only the first `limit` outputs need to be merged, everything behind them may end up in any
+    /// order. Input elements past the first `limit` of their side can't make it there, so they
+    /// are moved to the back of the output right away.
+    pub fn with_limit(mut self, limit: usize) -> Self {
+        unsafe {
+            let left_len = diff(self.left, self.left_end);
+            let right_len = diff(self.right, self.right_end);
+            let left_keep = std::cmp::min(limit, left_len);
+            let right_keep = std::cmp::min(limit, right_len);
+            let tail = self.output.add(left_keep + right_keep);
+            ptr::copy_nonoverlapping(self.left.add(left_keep), tail, left_len - left_keep);
+            let tail_right = tail.add(left_len - left_keep);
+            ptr::copy_nonoverlapping(
+                self.right.add(right_keep),
+                tail_right,
+                right_len - right_keep,
+            );
+            self.left_end = self.left.add(left_keep);
+            self.right_end = self.right.add(right_keep);
+            self.output_end = tail;
+            self.output_limit = self
+                .output
+                .add(std::cmp::min(limit, left_keep + right_keep));
+        }
+        self
+    }
     pub fn work_left(&self) -> usize {
-        diff(self.output, self.output_end)
+        // a step may merge a bit past the limit
+        let limit = std::cmp::max(self.output as *const T, self.output_limit);
+        diff(self.output, limit)
+    }
+    /// Moves all remaining input elements to the output without merging them. That's only
+    /// needed if the sort got aborted or a comparison panicked. We only ever write into the inputs
+    /// where the right one is in the output and done with already, so all elements not accounted
+    /// for in the output are still there, and afterwards the output holds every element exactly
+    /// once.
+    fn flush(&mut self) {
+        unsafe {
+            let left_len = diff(self.left, self.left_end);
+            ptr::copy_nonoverlapping(self.left, self.output, left_len);
+            let output = self.output.add(left_len);
+            // it's a no-op if the right input is in the output already
+            ptr::copy(self.right, output, diff(self.right, self.right_end));
+        }
+        self.output = self.output_end as *mut T;
     }
 }
 
-impl<T> Task for SliceMerge<T>
+impl<'f, T, F> Task for SliceMerge<'f, T, F>
 where
-    T: Copy + Ord + Sync + Send,
+    T: Send,
+    F: Comparator<T> + Sync,
 {
     fn step(&mut self) {
         assert!(self.output as *const T != self.output_end);
+        if self.compare.aborted() || self.output as *const T >= self.output_limit {
+            self.flush();
+            return;
+        }
         unsafe {
-            let left_work_end = std::cmp::min(self.left_end, self.left.add(self.work_size));
-            let right_work_end = std::cmp::min(self.right_end, self.right.add(self.work_size));
-            let mut left: *const T = self.left;
-            let mut right: *const T = self.right;
-            let mut output: *mut T = self.output;
-            while left < left_work_end && right < right_work_end {
-                let to_copy = if *left <= *right {
-                    get_and_increment(&mut left)
+            // we never need more than `work_left` elements from one side
+            let work_size = std::cmp::min(self.work_size, self.work_left());
+            let left_work_end = std::cmp::min(self.left_end, self.left.add(work_size));
+            let right_work_end = std::cmp::min(self.right_end, self.right.add(work_size));
+            let compare = self.compare;
+            if self.left < self.left_end
+                && self.right < self.right_end
+                && compare.compare(&*self.left_end.sub(1), &*self.right) != Ordering::Greater
+            {
+                // the inputs are in order already, just put them one after the other
+                self.flush();
+                return;
+            }
+            // The progress goes right back into self after every element: if a comparison
+            // panics, flush needs to know what's merged already, since with the right input in the
+            // output we might have written over where it started.
+
+            // primitive keys get merged a register at a time as far as possible
+            let mut inputs = [(self.left, left_work_end), (self.right, right_work_end)];
+            compare.merge_blocks(&mut inputs, &mut self.output);
+            self.left = inputs[0].0;
+            self.right = inputs[1].0;
+            // how often in a row the same side won
+            let (mut left_wins, mut right_wins) = (0, 0);
+            while self.left < left_work_end && self.right < right_work_end {
+                if left_wins >= MIN_GALLOP {
+                    // chances are the next ones come before `right` too, find them all at once
+                    let right = self.right;
+                    let count = gallop(self.left, left_work_end, |x| {
+                        compare.compare(x, &*right) != Ordering::Greater
+                    });
+                    ptr::copy_nonoverlapping(self.left, self.output, count);
+                    self.left = self.left.add(count);
+                    self.output = self.output.add(count);
+                    left_wins = 0;
+                } else if right_wins >= MIN_GALLOP {
+                    // the right input might be in the output (see half_copy)
+                    let left = self.left;
+                    let count = gallop(self.right, right_work_end, |x| {
+                        compare.compare(x, &*left) == Ordering::Less
+                    });
+                    ptr::copy(self.right, self.output, count);
+                    self.right = self.right.add(count);
+                    self.output = self.output.add(count);
+                    right_wins = 0;
+                } else if self.branchless {
+                    // for small elements a mispredicted branch costs more than the merge itself
+                    let wins = merge_branchless(
+                        &mut self.left,
+                        left_work_end,
+                        &mut self.right,
+                        right_work_end,
+                        &mut self.output,
+                        |a, b| compare.compare(a, b) == Ordering::Less,
+                    );
+                    left_wins = wins.0;
+                    right_wins = wins.1;
+                } else if compare.compare(&*self.left, &*self.right) != Ordering::Greater {
+                    ptr::copy_nonoverlapping(self.left, self.output, 1);
+                    self.left = self.left.add(1);
+                    self.output = self.output.add(1);
+                    left_wins += 1;
+                    right_wins = 0;
                 } else {
-                    get_and_increment(&mut right)
-                };
-                ptr::copy_nonoverlapping(to_copy, get_and_increment_mut(&mut output), 1);
+                    ptr::copy(self.right, self.output, 1);
+                    self.right = self.right.add(1);
+                    self.output = self.output.add(1);
+                    right_wins += 1;
+                    left_wins = 0;
+                }
             }
-            self.left = left;
-            self.right = right;
-            self.output = output;
             if self.left < self.left_end && self.right < self.right_end {
+                if self.output as *const T >= self.output_limit {
+                    // everything we need is merged, the rest can stay as it is
+                    self.flush();
+                }
                 // no side is finished yet
                 return;
             };
             // one side is finished, copy over the remainder from the other side
             assert!(self.left < self.left_end || self.right < self.right_end);
-            ptr::copy_nonoverlapping(self.right, self.output, diff(self.right, self.right_end));
+            ptr::copy(self.right, self.output, diff(self.right, self.right_end));
             ptr::copy_nonoverlapping(self.left, self.output, diff(self.left, self.left_end));
             self.output = self.output_end as *mut T;
-
-            pub unsafe fn get_and_increment_mut<T>(ptr: &mut *mut T) -> *mut T {
-                let old = *ptr;
-                *ptr = ptr.offset(1);
-                old
-            }
-            pub unsafe fn get_and_increment<T>(ptr: &mut *const T) -> *const T {
-                let old = *ptr;
-                *ptr = ptr.offset(1);
-                old
-            }
         }
     }
     fn is_finished(&self) -> bool {
@@ -97,7 +302,9 @@ where
             let output = from_raw_parts_mut(self.output, diff(self.output, self.output_end));
 
             // split on side at half (we might want to split the bigger side (?)
-            let (left_index, right_index) = split_for_merge(left, right, &|a, b| a < b);
+            let compare = self.compare;
+            let (left_index, right_index) =
+                split_for_merge(left, right, &|a, b| compare.compare(a, b) == Ordering::Less);
             let (left_left, left_right) = left.split_at(left_index);
 
             // split the right side at the same element than the left side
@@ -112,31 +319,140 @@ where
                 right_end: right_right.as_ptr().add(right_right.len()),
                 output: output_right.as_mut_ptr(),
                 output_end: output_right.as_ptr().add(output_right.len()),
+                output_limit: clamp(
+                    self.output_limit,
+                    output_right.as_ptr(),
+                    output_right.as_ptr().add(output_right.len()),
+                ),
                 work_size: self.work_size,
+                split_factor: self.split_factor,
+                branchless: self.branchless,
+                compare: self.compare,
             };
             // just merge the left-side slices here
+            if self.right >= self.output as *const T && self.right < self.output_end {
+                // The right input is in the output, and the other task is going to write over
+                // the part we still need. Move it to the back of our part of the output.
+                let moved = self.output.add(left_left.len());
+                ptr::copy(self.right, moved, right_left.len());
+                self.right = moved;
+            }
             self.left_end = self.left.add(left_left.len());
             self.right_end = self.right.add(right_left.len());
             self.output_end = self.output.add(output_left.len());
+            self.output_limit = std::cmp::min(self.output_limit, self.output_end);
             // println!("Parallel Merge: Left: , right: ",);
 
             runner(&mut vec![self, &mut other]);
         }
     }
     fn can_split(&self) -> bool {
-        return self.work_left() > self.work_size * 32;
+        return self.work_left() > self.work_size * self.split_factor;
     }
     fn fuse(&mut self, _other: &mut Self) {
         // Nothing to do here actually
     }
 }
 
+impl<'f, T, F> Drop for SliceMerge<'f, T, F>
+where
+    F: Comparator<T>,
+{
+    fn drop(&mut self) {
+        // we only get here unfinished if a comparison panicked
+        if self.output as *const T != self.output_end {
+            self.flush();
+        }
+    }
+}
+
 // difference between two pointer (it's in  std::ptr but only on nightly)
 fn diff<T>(left: *const T, right: *const T) -> usize {
     // assert!(right as usize >= left as usize);
     (right as usize - left as usize) / mem::size_of::<T>()
 }
 
+/// Elements up to this size get merged by `merge_branchless`.
+pub(crate) const BRANCHLESS_MAX_SIZE: usize = 16;
+
+/// True if `T` gets merged by `merge_branchless` by default: it has to be small, and plain data without any
+/// drop glue (that's as close to Copy as we can check here).
+fn branchless<T>() -> bool {
+    !mem::needs_drop::<T>() && mem::size_of::<T>() <= BRANCHLESS_MAX_SIZE
+}
+
+/// Merges until one of the inputs reaches its end, or one side won `MIN_GALLOP` times in a row,
+/// without branching on the comparisons: both sides advance by the outcome of the comparison as a
+/// number. Returns how often in a row the `(left, right)` side won at the end, as far as we know.
+/// The right input may be in the output like in `SliceMerge`.
+unsafe fn merge_branchless<T>(
+    left: &mut *const T,
+    left_end: *const T,
+    right: &mut *const T,
+    right_end: *const T,
+    output: &mut *mut T,
+    is_less: impl Fn(&T, &T) -> bool,
+) -> (usize, usize) {
+    loop {
+        // in chunks that both inputs have enough left for, checking for a streak after each one
+        let chunk = std::cmp::min(
+            MIN_GALLOP,
+            std::cmp::min(diff(*left, left_end), diff(*right, right_end)),
+        );
+        if chunk == 0 {
+            return (0, 0);
+        }
+        let (left_start, right_start) = (*left, *right);
+        for _ in 0..chunk {
+            // ties go to the left
+            let take_right = is_less(&**right, &**left);
+            let from = if take_right { *right } else { *left };
+            ptr::copy_nonoverlapping(from, *output, 1);
+            *output = output.add(1);
+            *right = right.add(take_right as usize);
+            *left = left.add(!take_right as usize);
+        }
+        if chunk == MIN_GALLOP {
+            if *right == right_start {
+                return (MIN_GALLOP, 0);
+            }
+            if *left == left_start {
+                return (0, MIN_GALLOP);
+            }
+        }
+    }
+}
+
+/// After how many wins in a row a merge starts galloping (like TimSort, but fixed).
+pub(crate) const MIN_GALLOP: usize = 7;
+
+/// How many elements from `start` on (up to `end`) fulfill `pred`, if it holds for a prefix of
+/// them. Exponential search, so a run of `k` elements takes about `2 log k` comparisons.
+pub(crate) unsafe fn gallop<T>(start: *const T, end: *const T, pred: impl Fn(&T) -> bool) -> usize {
+    let len = diff(start, end);
+    // pred holds for the first `low` elements, and not for the one at `high` (if it's in range)
+    let mut low = 0;
+    let mut high = 1;
+    while high <= len && pred(&*start.add(high - 1)) {
+        low = high;
+        high *= 2;
+    }
+    let mut high = std::cmp::min(high - 1, len);
+    while low < high {
+        let m = low + (high - low) / 2;
+        if pred(&*start.add(m)) {
+            low = m + 1;
+        } else {
+            high = m;
+        }
+    }
+    low
+}
+
+pub(crate) fn clamp<T>(ptr: *const T, start: *const T, end: *const T) -> *const T {
+    std::cmp::max(start, std::cmp::min(ptr, end))
+}
+
 // copied from rayon: https://github.com/rayon-rs/rayon/blob/master/src/slice/mergesort.rs
 /// Splits two sorted slices so that they can be merged in parallel.
 ///
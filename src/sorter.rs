@@ -0,0 +1,140 @@
+use crate::compare::Comparator;
+use crate::in_place::sort_in_place;
+use crate::{sort_half_buffer, sort_into, steal, ConfigError, MergesortConfig};
+use std::alloc::{alloc, dealloc, handle_alloc_error, Layout};
+use std::cmp::Ordering;
+use std::ptr::NonNull;
+
+/// Keeps a thread pool and scratch memory around for repeated sorts.
+pub struct Sorter {
+    pool: rayon::ThreadPool,
+    scratch: Arena,
+    config: MergesortConfig,
+}
+
+impl Sorter {
+    /// A sorter with one thread per core.
+    pub fn new() -> Self {
+        Self::with_threads(*steal::NUM_THREADS, 8)
+    }
+    /// A sorter with `threads` threads, thieves retry `backoffs` times on a victim before moving
+    /// on (see `steal::steal`).
+    pub fn with_threads(threads: usize, backoffs: usize) -> Self {
+        // the steal counters have one bit per thread
+        assert!(
+            threads <= *steal::NUM_THREADS && threads <= 64,
+            "at most {} threads supported",
+            std::cmp::min(*steal::NUM_THREADS, 64)
+        );
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .steal_callback(move |victim| steal::steal(backoffs, victim))
+            .build()
+            .expect("failed building the thread pool");
+        Sorter {
+            pool,
+            scratch: Arena::new(),
+            config: MergesortConfig::default(),
+        }
+    }
+    pub fn set_config(&mut self, config: MergesortConfig) -> Result<(), ConfigError> {
+        config.validate()?;
+        self.config = config;
+        Ok(())
+    }
+    pub fn sort<T>(&mut self, data: &mut [T])
+    where
+        T: Ord + Send,
+    {
+        self.sort_by(data, |a, b| a.cmp(b))
+    }
+    pub fn sort_by<T, F>(&mut self, data: &mut [T], compare: F)
+    where
+        T: Send,
+        F: Fn(&T, &T) -> Ordering + Sync,
+    {
+        self.sort_with(data, &compare)
+    }
+    fn sort_with<T, F>(&mut self, data: &mut [T], compare: &F)
+    where
+        T: Send,
+        F: Comparator<T> + Sync,
+    {
+        if data.len() <= 1 {
+            return;
+        }
+        let config = &self.config;
+        if config.in_place {
+            self.pool.install(|| sort_in_place(data, compare, config));
+        } else if config.half_buffer {
+            let to = self.scratch.get::<T>(data.len() - data.len() / 2);
+            self.pool
+                .install(|| sort_half_buffer(data, to, compare, config));
+        } else {
+            let to = self.scratch.get::<T>(data.len());
+            self.pool
+                .install(|| sort_into(data, to, compare, config, None, None));
+        }
+    }
+}
+
+impl Default for Sorter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Uninitialized memory, reused between sorts of any element type. It only ever grows.
+struct Arena {
+    ptr: *mut u8,
+    layout: Layout,
+}
+
+// it's just memory
+unsafe impl Send for Arena {}
+unsafe impl Sync for Arena {}
+
+impl Arena {
+    fn new() -> Self {
+        Arena {
+            ptr: std::ptr::null_mut(),
+            layout: Layout::from_size_align(0, 1).unwrap(),
+        }
+    }
+    /// Scratch space for `len` elements of type `T`. Nothing in it is initialized.
+    fn get<T>(&mut self, len: usize) -> &mut [T] {
+        let layout = Layout::array::<T>(len).expect("scratch buffer too big");
+        if layout.size() == 0 {
+            return unsafe { std::slice::from_raw_parts_mut(NonNull::dangling().as_ptr(), len) };
+        }
+        if layout.size() > self.layout.size() || layout.align() > self.layout.align() {
+            let new_layout = Layout::from_size_align(
+                std::cmp::max(layout.size(), self.layout.size()),
+                std::cmp::max(layout.align(), self.layout.align()),
+            )
+            .unwrap();
+            unsafe {
+                self.free();
+                self.ptr = alloc(new_layout);
+                if self.ptr.is_null() {
+                    handle_alloc_error(new_layout);
+                }
+            }
+            self.layout = new_layout;
+        }
+        unsafe { std::slice::from_raw_parts_mut(self.ptr as *mut T, len) }
+    }
+    unsafe fn free(&mut self) {
+        if !self.ptr.is_null() {
+            dealloc(self.ptr, self.layout);
+            self.ptr = std::ptr::null_mut();
+            self.layout = Layout::from_size_align(0, 1).unwrap();
+        }
+    }
+}
+
+impl Drop for Arena {
+    fn drop(&mut self) {
+        unsafe { self.free() }
+    }
+}
@@ -1,12 +1,15 @@
-use crate::slice_merge::SliceMerge;
+use crate::compare::Comparator;
+use crate::k_merge::co_rank;
+use crate::slice_merge::{clamp, gallop, SliceMerge, MIN_GALLOP};
 use adaptive_algorithms::Task;
+use std::cmp::Ordering;
 use std::mem;
 use std::ptr;
 use std::slice::{from_raw_parts, from_raw_parts_mut};
 
-pub struct ThreeMerge<T>
+pub struct ThreeMerge<'f, T, F>
 where
-    T: Copy + Ord,
+    F: Comparator<T>,
 {
     pub left: *const T,
     pub left_end: *const T,
@@ -17,13 +20,21 @@ where
     pub right_end: *const T,
     pub output: *mut T,
     pub output_end: *const T,
+    pub output_limit: *const T, // everything from here on may stay unmerged
     pub work_size: usize,
+    pub split_factor: usize, // only split with more than split_factor * work_size work left
+    pub compare: &'f F,
+}
+unsafe impl<'f, T, F> Send for ThreeMerge<'f, T, F>
+where
+    T: Send,
+    F: Comparator<T> + Sync,
+{
 }
-unsafe impl<T> Send for ThreeMerge<T> where T: Copy + Ord {}
 // unsafe impl<T> Sync for SliceMerge<T> where T: Copy + Ord {}
-impl<T> ThreeMerge<T>
+impl<'f, T, F> ThreeMerge<'f, T, F>
 where
-    T: Copy + Ord,
+    F: Comparator<T>,
 {
     pub fn new(
         left: &[T],
@@ -31,101 +42,225 @@ where
         right: &[T],
         output: &mut [T],
         work_size: usize,
-    ) -> ThreeMerge<T> {
+        split_factor: usize,
+        compare: &'f F,
+    ) -> ThreeMerge<'f, T, F> {
         assert!(left.len() + right.len() + middle.len() == output.len());
         unsafe {
             return ThreeMerge {
                 left: left.as_ptr(),
                 left_end: left.as_ptr().add(left.len()),
                 middle: middle.as_ptr(),
-                middle_end: middle.as_ptr().add(left.len()),
+                middle_end: middle.as_ptr().add(middle.len()),
                 right: right.as_ptr(),
                 right_end: right.as_ptr().add(right.len()),
                 output: output.as_mut_ptr(),
                 output_end: output.as_ptr().add(output.len()),
+                output_limit: output.as_ptr().add(output.len()),
                 work_size,
+                split_factor,
+                compare,
             };
         }
     }
+    /// Only the first `limit` outputs need to be merged (see SliceMerge::with_limit).
+    pub fn with_limit(mut self, limit: usize) -> Self {
+        unsafe {
+            let mut kept = 0;
+            let mut tail = self.output.add(diff(self.output, self.output_end));
+            // the tails go to the back in reverse, their order doesn't matter
+            for (start, end) in [
+                (self.right, &mut self.right_end),
+                (self.middle, &mut self.middle_end),
+                (self.left, &mut self.left_end),
+            ] {
+                let len = diff(start, *end);
+                let keep = std::cmp::min(limit, len);
+                tail = tail.sub(len - keep);
+                ptr::copy_nonoverlapping(start.add(keep), tail, len - keep);
+                *end = start.add(keep);
+                kept += keep;
+            }
+            self.output_end = tail;
+            self.output_limit = self.output.add(std::cmp::min(limit, kept));
+        }
+        self
+    }
     pub fn work_left(&self) -> usize {
-        diff(self.output, self.output_end)
+        // a step may merge a bit past the limit
+        let limit = std::cmp::max(self.output as *const T, self.output_limit);
+        diff(self.output, limit)
+    }
+    /// Moves all remaining input elements to the output without merging them (see SliceMerge).
+    fn flush(&mut self) {
+        unsafe {
+            let mut output = self.output;
+            for &(start, end) in &[
+                (self.left, self.left_end),
+                (self.middle, self.middle_end),
+                (self.right, self.right_end),
+            ] {
+                ptr::copy_nonoverlapping(start, output, diff(start, end));
+                output = output.add(diff(start, end));
+            }
+        }
+        self.output = self.output_end as *mut T;
     }
 }
 
-impl<T> Task for ThreeMerge<T>
+impl<'f, T, F> Task for ThreeMerge<'f, T, F>
 where
-    T: Copy + Ord + Sync + Send,
+    T: Send,
+    F: Comparator<T> + Sync,
 {
     fn step(&mut self) {
-        // self.check();
+        debug_assert!(self.is_consistent());
         assert!(self.output as *const T != self.output_end);
+        if self.compare.aborted() || self.output as *const T >= self.output_limit {
+            self.flush();
+            return;
+        }
+        let compare = self.compare;
+        // we never need more than `work_left` elements from one side
+        let work_size = std::cmp::min(self.work_size, self.work_left());
         unsafe {
-            let left_work_end = std::cmp::min(self.left_end, self.left.add(self.work_size));
-            let middle_work_end = std::cmp::min(self.middle_end, self.middle.add(self.work_size));
-            let right_work_end = std::cmp::min(self.right_end, self.right.add(self.work_size));
-            let mut left: *const T = self.left;
-            let mut middle: *const T = self.middle;
-            let mut right: *const T = self.right;
-            let mut output: *mut T = self.output;
-            let mut left_ = *left;
-            let mut middle_ = *middle;
-            let mut right_ = *right;
-
-            // while left < left_work_end && right < right_work_end && middle < middle_work_end {
-            // let mut to_copy;
-            output = output.sub(1);
-
-            'outer: loop {
-                output = output.add(1);
+            // we can only look at the current elements if no side is empty (that can happen right
+            // after a split)
+            if self.left < self.left_end
+                && self.right < self.right_end
+                && self.middle < self.middle_end
+            {
+                let left_work_end = std::cmp::min(self.left_end, self.left.add(work_size));
+                let middle_work_end = std::cmp::min(self.middle_end, self.middle.add(work_size));
+                let right_work_end = std::cmp::min(self.right_end, self.right.add(work_size));
+                let mut left: *const T = self.left;
+                let mut middle: *const T = self.middle;
+                let mut right: *const T = self.right;
+                let mut output: *mut T = self.output;
+                // we only move values around with ptr::copy, the input is considered
+                // uninitialized once we're done with it, so this works for non-Copy types too
+                let less = |a: *const T, b: *const T| compare.compare(&*a, &*b) == Ordering::Less;
+                if !less(middle, self.left_end.sub(1)) && !less(right, self.middle_end.sub(1)) {
+                    // the inputs are in order already, just put them one after the other
+                    self.flush();
+                    return;
+                }
+                // see SliceMerge, every input keeps at least one element
+                let mut inputs = [
+                    (left, left_work_end),
+                    (middle, middle_work_end),
+                    (right, right_work_end),
+                ];
+                compare.merge_blocks(&mut inputs, &mut output);
+                left = inputs[0].0;
+                middle = inputs[1].0;
+                right = inputs[2].0;
 
-                if left_ <= middle_ {
-                    while right_ < left_ {
-                        *output = right_;
-                        right = right.add(1);
-                        if right == right_work_end {
+                // how often in a row the same input won
+                let (mut left_wins, mut middle_wins, mut right_wins) = (0, 0, 0);
+                'outer: loop {
+                    if !less(middle, left) {
+                        while less(right, left) {
+                            ptr::copy_nonoverlapping(
+                                get_and_increment(&mut right),
+                                get_and_increment_mut(&mut output),
+                                1,
+                            );
+                            right_wins += 1;
+                            left_wins = 0;
+                            if right_wins >= MIN_GALLOP {
+                                let count = gallop(right, right_work_end, |x| less(x, left));
+                                ptr::copy_nonoverlapping(right, output, count);
+                                right = right.add(count);
+                                output = output.add(count);
+                                right_wins = 0;
+                            }
+                            if right == right_work_end {
+                                break 'outer;
+                            }
+                        }
+                        ptr::copy_nonoverlapping(
+                            get_and_increment(&mut left),
+                            get_and_increment_mut(&mut output),
+                            1,
+                        );
+                        left_wins += 1;
+                        middle_wins = 0;
+                        right_wins = 0;
+                        if left_wins >= MIN_GALLOP {
+                            // ties go to the left
+                            let count = gallop(left, left_work_end, |x| {
+                                !less(middle, x) && !less(right, x)
+                            });
+                            ptr::copy_nonoverlapping(left, output, count);
+                            left = left.add(count);
+                            output = output.add(count);
+                            left_wins = 0;
+                        }
+                        if left == left_work_end {
                             break 'outer;
                         }
-                        output = output.add(1);
-                        right_ = *right;
-                    }
-                    *output = left_;
-                    left = left.add(1);
-                    if left == left_work_end {
-                        break 'outer;
-                    }
-                    left_ = *left;
-                } else {
-                    while right_ <= middle_ {
-                        *output = right_;
-                        right = right.add(1);
-                        if right == right_work_end {
+                    } else {
+                        // ties go to the middle to keep the merge stable
+                        while less(right, middle) {
+                            ptr::copy_nonoverlapping(
+                                get_and_increment(&mut right),
+                                get_and_increment_mut(&mut output),
+                                1,
+                            );
+                            right_wins += 1;
+                            middle_wins = 0;
+                            if right_wins >= MIN_GALLOP {
+                                let count = gallop(right, right_work_end, |x| less(x, middle));
+                                ptr::copy_nonoverlapping(right, output, count);
+                                right = right.add(count);
+                                output = output.add(count);
+                                right_wins = 0;
+                            }
+                            if right == right_work_end {
+                                break 'outer;
+                            }
+                        }
+                        ptr::copy_nonoverlapping(
+                            get_and_increment(&mut middle),
+                            get_and_increment_mut(&mut output),
+                            1,
+                        );
+                        middle_wins += 1;
+                        left_wins = 0;
+                        right_wins = 0;
+                        if middle_wins >= MIN_GALLOP {
+                            let count = gallop(middle, middle_work_end, |x| {
+                                less(x, left) && !less(right, x)
+                            });
+                            ptr::copy_nonoverlapping(middle, output, count);
+                            middle = middle.add(count);
+                            output = output.add(count);
+                            middle_wins = 0;
+                        }
+                        if middle == middle_work_end {
                             break 'outer;
                         }
-                        output = output.add(1);
-                        right_ = *right;
-                    }
-                    *output = middle_;
-                    middle = middle.add(1);
-                    if middle == middle_work_end {
-                        break 'outer;
                     }
-                    middle_ = *middle;
                 }
+                self.left = left;
+                self.middle = middle;
+                self.right = right;
+                self.output = output;
+                if self.left < self.left_end
+                    && self.right < self.right_end
+                    && self.middle < self.middle_end
+                {
+                    if self.output as *const T >= self.output_limit {
+                        // everything we need is merged, the rest can stay as it is
+                        self.flush();
+                    }
+                    // no side is finished yet
+                    return;
+                };
             }
-            output = output.add(1);
-            // ptr::copy_nonoverlapping(to_copy, get_and_increment_mut(&mut output), 1);
-            self.left = left;
-            self.middle = middle;
-            self.right = right;
-            self.output = output;
-            if self.left < self.left_end
-                && self.right < self.right_end
-                && self.middle < self.middle_end
-            {
-                // no side is finished yet
-                return;
-            };
-            // one side is finished, copy over the remainder from the other side
+            let limit = self.work_left();
+            // one side is finished, merge the other two
             let left = from_raw_parts(self.left, diff(self.left, self.left_end));
             let middle = from_raw_parts(self.middle, diff(self.middle, self.middle_end));
             let right = from_raw_parts(self.right, diff(self.right, self.right_end));
@@ -133,20 +268,42 @@ where
             assert_eq!(left.len() + right.len() + middle.len(), output.len());
 
             if self.left == self.left_end {
-                SliceMerge::new(middle, right, output, self.work_size).run();
+                SliceMerge::new(
+                    middle,
+                    right,
+                    output,
+                    self.work_size,
+                    self.split_factor,
+                    compare,
+                )
+                .with_limit(limit)
+                .run();
             } else if self.middle == self.middle_end {
-                SliceMerge::new(left, right, output, self.work_size).run();
+                SliceMerge::new(
+                    left,
+                    right,
+                    output,
+                    self.work_size,
+                    self.split_factor,
+                    compare,
+                )
+                .with_limit(limit)
+                .run();
             } else if self.right == self.right_end {
-                SliceMerge::new(left, middle, output, self.work_size).run();
+                SliceMerge::new(
+                    left,
+                    middle,
+                    output,
+                    self.work_size,
+                    self.split_factor,
+                    compare,
+                )
+                .with_limit(limit)
+                .run();
             }
             self.output = self.output_end as *mut T;
             return;
 
-            // // assert!(self.left < self.left_end || self.right < self.right_end);
-            // ptr::copy_nonoverlapping(self.right, self.output, diff(self.right, self.right_end));
-            // ptr::copy_nonoverlapping(self.left, self.output, diff(self.left, self.left_end));
-            // self.output = self.output_end as *mut T;
-
             pub unsafe fn get_and_increment_mut<T>(ptr: &mut *mut T) -> *mut T {
                 let old = *ptr;
                 *ptr = ptr.offset(1);
@@ -172,16 +329,15 @@ where
             let output = from_raw_parts_mut(self.output, diff(self.output, self.output_end));
 
             assert!(left.len() + right.len() + middle.len() == output.len());
-            assert!(output.len() > 100);
-            // split on side at half (we might want to split the bigger side (?)
-            let (left_index, right_index) = split_for_merge(left, right, &|a, b| a < b);
-            let (left_index2, middle_index) = split_for_merge(left, middle, &|a, b| a < b);
-            assert_eq!(left_index, left_index2);
+            // split in the middle of the output we still need, and find where that is in each
+            // input
+            let compare = self.compare;
+            let is_less = |a: &T, b: &T| compare.compare(a, b) == Ordering::Less;
+            let cuts = co_rank(&[left, middle, right], self.work_left() / 2, &is_less);
+            let (left_index, middle_index, right_index) = (cuts[0], cuts[1], cuts[2]);
             let (left_left, left_right) = left.split_at(left_index);
-
-            // split the right side at the same element than the left side
-            let (right_left, right_right) = right.split_at(right_index);
             let (middle_left, middle_right) = middle.split_at(middle_index);
+            let (right_left, right_right) = right.split_at(right_index);
             let (output_left, output_right) =
                 output.split_at_mut(right_left.len() + left_left.len() + middle_left.len());
             // create another merging task will all right side slices.
@@ -194,92 +350,61 @@ where
                 right_end: right_right.as_ptr().add(right_right.len()),
                 output: output_right.as_mut_ptr(),
                 output_end: output_right.as_ptr().add(output_right.len()),
+                output_limit: clamp(
+                    self.output_limit,
+                    output_right.as_ptr(),
+                    output_right.as_ptr().add(output_right.len()),
+                ),
                 work_size: self.work_size,
+                split_factor: self.split_factor,
+                compare: self.compare,
             };
             // just merge the left-side slices here
             self.left_end = self.left.add(left_left.len());
             self.middle_end = self.middle.add(middle_left.len());
             self.right_end = self.right.add(right_left.len());
             self.output_end = self.output.add(output_left.len());
+            self.output_limit = std::cmp::min(self.output_limit, self.output_end);
             // println!("Parallel Merge: Left: , right: ",);
-            // self.check();
-            // other.check();
+            debug_assert!(self.is_consistent() && other.is_consistent());
 
             runner(&mut vec![self, &mut other]);
         }
     }
     fn can_split(&self) -> bool {
-        return self.work_left() > self.work_size * 32;
+        return self.work_left() > self.work_size * self.split_factor;
     }
     fn fuse(&mut self, _other: &mut Self) {
         // Nothing to do here actually
     }
 }
-impl<T> ThreeMerge<T>
+impl<'f, T, F> ThreeMerge<'f, T, F>
 where
-    T: Ord + Copy,
+    F: Comparator<T>,
 {
-    fn check(&self) {
-        assert_eq!(
-            diff(self.left, self.left_end)
-                + diff(self.right, self.right_end)
-                + diff(self.middle, self.middle_end),
-            diff(self.output, self.output_end)
-        );
+    /// True if the inputs have exactly as many elements left as there's room in the output.
+    fn is_consistent(&self) -> bool {
+        diff(self.left, self.left_end)
+            + diff(self.right, self.right_end)
+            + diff(self.middle, self.middle_end)
+            == diff(self.output, self.output_end)
     }
 }
 
-// difference between two pointer (it's in  std::ptr but only on nightly)
-fn diff<T>(left: *const T, right: *const T) -> usize {
-    // assert!(right as usize >= left as usize);
-    (right as usize - left as usize) / mem::size_of::<T>()
-}
-
-// copied from rayon: https://github.com/rayon-rs/rayon/blob/master/src/slice/mergesort.rs
-/// Splits two sorted slices so that they can be merged in parallel.
-///
-/// Returns two indices `(a, b)` so that slices `left[..a]` and `right[..b]` come before
-/// `left[a..]` and `right[b..]`.
-fn split_for_merge<T, F>(left: &[T], right: &[T], is_less: &F) -> (usize, usize)
+impl<'f, T, F> Drop for ThreeMerge<'f, T, F>
 where
-    F: Fn(&T, &T) -> bool,
+    F: Comparator<T>,
 {
-    let left_len = left.len();
-    let right_len = right.len();
-
-    // if left_len >= right_len {
-    let left_mid = left_len / 2;
-
-    // Find the first element in `right` that is greater than or equal to `left[left_mid]`.
-    let mut a = 0;
-    let mut b = right_len;
-    while a < b {
-        let m = a + (b - a) / 2;
-        if is_less(&right[m], &left[left_mid]) {
-            a = m + 1;
-        } else {
-            b = m;
+    fn drop(&mut self) {
+        // we only get here unfinished if a comparison panicked
+        if self.output as *const T != self.output_end {
+            self.flush();
         }
     }
+}
 
-    (left_mid, a)
-    /*
-    } else {
-        let right_mid = right_len / 2;
-
-        // Find the first element in `left` that is greater than `right[right_mid]`.
-        let mut a = 0;
-        let mut b = left_len;
-        while a < b {
-            let m = a + (b - a) / 2;
-            if is_less(&right[right_mid], &left[m]) {
-                b = m;
-            } else {
-                a = m + 1;
-            }
-        }
-
-        (a, right_mid)
-    }
-           */
+// difference between two pointer (it's in  std::ptr but only on nightly)
+fn diff<T>(left: *const T, right: *const T) -> usize {
+    // assert!(right as usize >= left as usize);
+    (right as usize - left as usize) / mem::size_of::<T>()
 }
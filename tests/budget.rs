@@ -0,0 +1,94 @@
+mod common;
+
+use mergesort::{mergesort_by_with_budget, mergesort_with_budget, Strategy};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+#[test]
+pub fn strategies() {
+    let pool = adaptive_algorithms::rayon::get_thread_pool();
+    let len = 100_001;
+    let v: Vec<u64> = std::iter::repeat_with(rand::random).take(len).collect();
+    let mut expected = v.clone();
+    expected.sort();
+    for &(budget, strategy) in &[
+        (usize::MAX, Strategy::FullBuffer),
+        (8 * len, Strategy::FullBuffer),
+        (8 * len - 1, Strategy::HalfBuffer),
+        (8 * (len / 2 + 1), Strategy::HalfBuffer),
+        (8 * (len / 2), Strategy::InPlace),
+        (0, Strategy::InPlace),
+    ] {
+        let mut v = v.clone();
+        assert_eq!(
+            pool.install(|| mergesort_with_budget(&mut v, budget)),
+            strategy
+        );
+        assert_eq!(v, expected);
+    }
+}
+
+#[test]
+pub fn budget_thresholds() {
+    // a budget right at each strategy's threshold takes it, a byte less takes the next one
+    let pool = adaptive_algorithms::rayon::get_thread_pool();
+    let size = std::mem::size_of::<(u32, usize)>();
+    for &len in &[2, 3, 20000, 20001] {
+        let (v, expected) = common::tagged(len, 8);
+        let half = len - len / 2;
+        for &(budget, strategy) in &[
+            (size * len, Strategy::FullBuffer),
+            (size * len - 1, Strategy::HalfBuffer),
+            (size * half, Strategy::HalfBuffer),
+            (size * half - 1, Strategy::InPlace),
+        ] {
+            let mut v = v.clone();
+            assert_eq!(
+                pool.install(|| mergesort_by_with_budget(&mut v, budget, common::by_key)),
+                strategy
+            );
+            assert_eq!(v, expected);
+        }
+    }
+}
+
+#[test]
+pub fn budget_panic() {
+    // a panic at every comparison, without a thread pool so every run compares the same
+    let len = 300;
+    let original: Vec<String> = (0..len)
+        .map(|_| (rand::random::<u32>() % 100).to_string())
+        .collect();
+    let mut expected = original.clone();
+    expected.sort();
+    let size = std::mem::size_of::<String>();
+    for &(budget, strategy) in &[
+        (size * len, Strategy::FullBuffer),
+        (size * (len / 2), Strategy::HalfBuffer),
+        (0, Strategy::InPlace),
+    ] {
+        for panic_at in 0.. {
+            let mut v = original.clone();
+            let comparisons = AtomicUsize::new(0);
+            let result = catch_unwind(AssertUnwindSafe(|| {
+                mergesort_by_with_budget(&mut v, budget, |a, b| {
+                    if comparisons.fetch_add(1, Ordering::Relaxed) == panic_at {
+                        panic!("comparison failed");
+                    }
+                    a.cmp(b)
+                })
+            }));
+            // it's still a permutation of the input
+            let done = result.is_ok();
+            if done {
+                assert_eq!(result.unwrap(), strategy);
+                assert_eq!(v, expected);
+            }
+            v.sort();
+            assert_eq!(v, expected);
+            if done {
+                break;
+            }
+        }
+    }
+}
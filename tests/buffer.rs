@@ -0,0 +1,30 @@
+use mergesort::{mergesort_with_buffer, BufferTooSmall};
+use std::mem::MaybeUninit;
+
+#[test]
+pub fn reuse_buffer() {
+    let pool = adaptive_algorithms::rayon::get_thread_pool();
+    let mut scratch: Vec<MaybeUninit<String>> = (0..20000).map(|_| MaybeUninit::uninit()).collect();
+    for &len in &[0, 1, 100, 3usize.pow(9), 20000] {
+        let mut v: Vec<String> = std::iter::repeat_with(rand::random::<u32>)
+            .take(len)
+            .map(|x| x.to_string())
+            .collect();
+        let mut expected = v.clone();
+        expected.sort();
+        pool.install(|| mergesort_with_buffer(&mut v, &mut scratch))
+            .unwrap();
+        assert_eq!(v, expected);
+    }
+}
+
+#[test]
+pub fn buffer_too_small() {
+    let mut v = vec![3, 2, 1];
+    let mut scratch = vec![MaybeUninit::uninit(); 2];
+    assert_eq!(
+        mergesort_with_buffer(&mut v, &mut scratch),
+        Err(BufferTooSmall { needed: 3, got: 2 })
+    );
+    assert_eq!(v, vec![3, 2, 1]);
+}
@@ -0,0 +1,18 @@
+//! Helpers shared by the tests.
+use std::cmp::Ordering;
+
+/// `len` random keys below `keys`, each tagged with its position so ties show up if they get
+/// reordered. Also gives the result of a stable sort by key.
+pub fn tagged(len: usize, keys: u32) -> (Vec<(u32, usize)>, Vec<(u32, usize)>) {
+    let v: Vec<(u32, usize)> = (0..len)
+        .map(|i| (rand::random::<u32>() % keys, i))
+        .collect();
+    let mut expected = v.clone();
+    expected.sort_by_key(|x| x.0);
+    (v, expected)
+}
+
+/// Compares tagged elements by their key only.
+pub fn by_key(a: &(u32, usize), b: &(u32, usize)) -> Ordering {
+    a.0.cmp(&b.0)
+}
@@ -0,0 +1,116 @@
+mod common;
+
+use mergesort::{ConfigError, MergesortConfig};
+
+#[test]
+pub fn custom_config() {
+    let pool = adaptive_algorithms::rayon::get_thread_pool();
+    for &(blocksize, merge_work_size, split_factor) in &[(1, 1, 3), (32, 500, 4), (1000, 7, 100)] {
+        let mut v: Vec<u32> = std::iter::repeat_with(rand::random).take(100_000).collect();
+        let config = MergesortConfig::new()
+            .blocksize(blocksize)
+            .merge_work_size(merge_work_size)
+            .split_factor(split_factor);
+        pool.install(|| config.sort(&mut v)).unwrap();
+        assert!(v.windows(2).all(|w| w[0] <= w[1]));
+    }
+}
+
+#[test]
+pub fn arities() {
+    let pool = adaptive_algorithms::rayon::get_thread_pool();
+    for &arity in &[2, 3, 4] {
+        for &len in &[0, 1, 100, 1000, 50000] {
+            // few distinct keys, so there's lots of ties to keep in order
+            let (mut v, expected) = common::tagged(len, 16);
+            let config = MergesortConfig::new().blocksize(10).arity(arity);
+            pool.install(|| config.sort_by(&mut v, common::by_key))
+                .unwrap();
+            assert_eq!(v, expected);
+        }
+    }
+}
+
+#[test]
+pub fn half_buffer() {
+    // odd lengths leave the right half one longer than the buffer holds of the left, odd block and
+    // work sizes make the merge split at uneven points
+    let pool = adaptive_algorithms::rayon::get_thread_pool();
+    for &(blocksize, merge_work_size) in &[(1, 1), (3, 7), (81, 5)] {
+        for &len in &[0, 1, 2, 3, 5, 101, 1001, 3usize.pow(9), 50001] {
+            let (mut v, expected) = common::tagged(len, 16);
+            let config = MergesortConfig::new()
+                .half_buffer(true)
+                .blocksize(blocksize)
+                .merge_work_size(merge_work_size)
+                .split_factor(3);
+            pool.install(|| config.sort_by(&mut v, common::by_key))
+                .unwrap();
+            assert_eq!(v, expected);
+            // the left half all bigger than the right, so the right one goes first
+            let mut v: Vec<(u32, usize)> = (0..len).map(|i| ((i < len / 2) as u32, i)).collect();
+            let mut expected = v.clone();
+            expected.sort_by_key(|x| x.0);
+            pool.install(|| config.sort_by(&mut v, common::by_key))
+                .unwrap();
+            assert_eq!(v, expected);
+        }
+    }
+}
+
+#[test]
+pub fn in_place() {
+    // with one or two keys nearly every comparison in the rotations is a tie
+    let pool = adaptive_algorithms::rayon::get_thread_pool();
+    for &keys in &[1, 2, 16] {
+        for &blocksize in &[1, 20] {
+            for &len in &[0, 1, 2, 3, 100, 1001, 50000] {
+                let (mut v, expected) = common::tagged(len, keys);
+                let config = MergesortConfig::new()
+                    .in_place(true)
+                    .blocksize(blocksize)
+                    .merge_work_size(10)
+                    .split_factor(4);
+                pool.install(|| config.sort_by(&mut v, common::by_key))
+                    .unwrap();
+                assert_eq!(v, expected);
+            }
+        }
+    }
+}
+
+#[test]
+pub fn invalid_config() {
+    let mut v = vec![3, 2, 1];
+    let config = MergesortConfig::new();
+    assert_eq!(
+        config.blocksize(0).sort(&mut v),
+        Err(ConfigError::ZeroBlocksize)
+    );
+    assert_eq!(
+        config.merge_work_size(0).sort(&mut v),
+        Err(ConfigError::ZeroMergeWorkSize)
+    );
+    assert_eq!(
+        config.split_factor(2).sort(&mut v),
+        Err(ConfigError::SplitFactorTooSmall(2))
+    );
+    assert_eq!(
+        config.arity(5).sort(&mut v),
+        Err(ConfigError::UnsupportedArity(5))
+    );
+    assert_eq!(
+        config.blocksize(usize::MAX / 2).sort(&mut v),
+        Err(ConfigError::Overflow)
+    );
+    assert_eq!(
+        config.in_place(true).half_buffer(true).sort(&mut v),
+        Err(ConfigError::InPlaceWith("half_buffer"))
+    );
+    assert_eq!(
+        config.in_place(true).natural(true).sort(&mut v),
+        Err(ConfigError::InPlaceWith("natural"))
+    );
+    // nothing happened to the data
+    assert_eq!(v, vec![3, 2, 1]);
+}
@@ -0,0 +1,38 @@
+use mergesort::{mergesort_into, mergesort_to_vec};
+use std::mem::MaybeUninit;
+
+#[test]
+pub fn into_uninit() {
+    let pool = adaptive_algorithms::rayon::get_thread_pool();
+    // lengths around powers of three, so the output lands in either buffer
+    let lengths = (0..12)
+        .map(|k| 81 * 3usize.pow(k / 2) + (k as usize % 2) * 100)
+        .chain(vec![0, 1, 2, 80, 81, 82, 10000, 12345]);
+    for len in lengths {
+        let src: Vec<String> = std::iter::repeat_with(rand::random::<u32>)
+            .take(len)
+            .map(|x| x.to_string())
+            .collect();
+        let mut expected = src.clone();
+        expected.sort();
+        let mut dst: Vec<MaybeUninit<String>> = (0..len).map(|_| MaybeUninit::uninit()).collect();
+        pool.install(|| mergesort_into(&src, &mut dst));
+        let dst: Vec<String> = dst
+            .into_iter()
+            .map(|x| unsafe { x.assume_init() })
+            .collect();
+        assert_eq!(dst, expected);
+    }
+}
+
+#[test]
+pub fn to_vec() {
+    let src: Vec<u64> = std::iter::repeat_with(rand::random)
+        .take(3usize.pow(10) + 3)
+        .collect();
+    let pool = adaptive_algorithms::rayon::get_thread_pool();
+    let sorted = pool.install(|| mergesort_to_vec(&src));
+    let mut expected = src.clone();
+    expected.sort();
+    assert_eq!(sorted, expected);
+}
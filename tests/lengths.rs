@@ -0,0 +1,67 @@
+use mergesort::{mergesort_by, MergesortConfig};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+#[test]
+pub fn all_lengths() {
+    // tiny blocks give deep merge trees with all kinds of piece lengths
+    let pool = adaptive_algorithms::rayon::get_thread_pool();
+    let config = MergesortConfig::new().blocksize(2).merge_work_size(4);
+    for len in 0..2000 {
+        let mut v: Vec<(u8, usize)> = (0..len).map(|i| (rand::random::<u8>() % 4, i)).collect();
+        pool.install(|| config.sort_by(&mut v, |a, b| a.0.cmp(&b.0)))
+            .unwrap();
+        // stable, so the indices are sorted too
+        assert!(v.windows(2).all(|w| w[0] <= w[1]), "length {}", len);
+    }
+}
+
+#[test]
+pub fn balanced_merges() {
+    // lengths that are nowhere near a power of three still get a balanced merge tree
+    for &len in &[3usize.pow(11) + 1, 3usize.pow(11) * 2 + 81, 1_000_000] {
+        let mut v: Vec<u32> = std::iter::repeat_with(rand::random).take(len).collect();
+        let comparisons = AtomicUsize::new(0);
+        mergesort_by(&mut v, |a, b| {
+            comparisons.fetch_add(1, Ordering::Relaxed);
+            a.cmp(b)
+        });
+        assert!(v.windows(2).all(|w| w[0] <= w[1]));
+        let log = (len as f64).log2();
+        assert!((comparisons.load(Ordering::Relaxed) as f64) < 1.5 * len as f64 * log);
+    }
+}
+
+#[test]
+pub fn skewed_values() {
+    // blocks from very different value ranges, so merges have inputs that barely overlap
+    let pool = adaptive_algorithms::rayon::get_thread_pool();
+    let len = 500_000;
+    let mut v: Vec<(u32, usize)> = (0..len)
+        .map(|i| {
+            let range = [1, 10, 1 << 20, u32::MAX][(i / 1000) % 4];
+            (rand::random::<u32>() % range, i)
+        })
+        .collect();
+    pool.install(|| mergesort_by(&mut v, |a, b| a.0.cmp(&b.0)));
+    assert!(v.windows(2).all(|w| w[0] <= w[1]));
+}
+
+#[test]
+pub fn presorted_is_cheap() {
+    // the leaves take about a comparison per element, the merges almost nothing
+    let len = 1_000_000;
+    let mut v: Vec<u32> = (0..len).collect();
+    let comparisons = AtomicUsize::new(0);
+    mergesort_by(&mut v, |a, b| {
+        comparisons.fetch_add(1, Ordering::Relaxed);
+        a.cmp(b)
+    });
+    assert!(v.iter().copied().eq(0..len));
+    // debug builds check the result with another comparison per element
+    let check = if cfg!(debug_assertions) {
+        len as usize
+    } else {
+        0
+    };
+    assert!(comparisons.load(Ordering::Relaxed) < 2 * len as usize + check);
+}
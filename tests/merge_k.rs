@@ -0,0 +1,57 @@
+use mergesort::{merge_k, merge_k_by};
+
+fn sorted_runs(k: usize, max: u32) -> Vec<Vec<u32>> {
+    (0..k)
+        .map(|_| {
+            let len = rand::random::<usize>() % 20000;
+            let mut v: Vec<u32> = std::iter::repeat_with(|| rand::random::<u32>() % max)
+                .take(len)
+                .collect();
+            v.sort();
+            v
+        })
+        .collect()
+}
+
+#[test]
+pub fn merge_many() {
+    let pool = adaptive_algorithms::rayon::get_thread_pool();
+    for &k in &[0, 1, 2, 3, 5, 8, 17, 64] {
+        let runs = sorted_runs(k, u32::MAX);
+        let inputs: Vec<&[u32]> = runs.iter().map(|run| &run[..]).collect();
+        let mut out = vec![0; runs.iter().map(|run| run.len()).sum()];
+        pool.install(|| merge_k(&inputs, &mut out));
+        let mut expected = runs.concat();
+        expected.sort();
+        assert_eq!(out, expected);
+    }
+}
+
+#[test]
+pub fn merge_with_empty_inputs() {
+    let runs = vec![vec![], vec![3, 5], vec![], vec![1, 4], vec![]];
+    let inputs: Vec<&[u32]> = runs.iter().map(|run| &run[..]).collect();
+    let mut out = vec![0; 4];
+    merge_k(&inputs, &mut out);
+    assert_eq!(out, vec![1, 3, 4, 5]);
+}
+
+#[test]
+pub fn merge_k_stable() {
+    // few distinct keys, tag every element with its input
+    let runs: Vec<Vec<(u32, usize)>> = sorted_runs(24, 50)
+        .into_iter()
+        .enumerate()
+        .map(|(i, run)| run.into_iter().map(|x| (x, i)).collect())
+        .collect();
+    let inputs: Vec<&[(u32, usize)]> = runs.iter().map(|run| &run[..]).collect();
+    let mut out = vec![(0, 0); runs.iter().map(|run| run.len()).sum()];
+    let pool = adaptive_algorithms::rayon::get_thread_pool();
+    pool.install(|| merge_k_by(&inputs, &mut out, |a, b| a.0.cmp(&b.0)));
+    let mut expected = runs.concat();
+    expected.sort();
+    assert_eq!(out, expected);
+    // and the same without a pool
+    merge_k_by(&inputs, &mut out, |a, b| a.0.cmp(&b.0));
+    assert_eq!(out, expected);
+}
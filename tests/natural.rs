@@ -0,0 +1,82 @@
+use mergesort::{mergesort_by_runs, mergesort_runs, MergesortConfig};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// sorted batches, every other one descending, with lots of equal keys
+fn batches(count: usize) -> (Vec<(u32, usize)>, Vec<usize>) {
+    let mut v = Vec::new();
+    let mut boundaries = Vec::new();
+    for batch in 0..count {
+        let len = rand::random::<usize>() % 5000;
+        let mut b: Vec<u32> = std::iter::repeat_with(|| rand::random::<u32>() % 100)
+            .take(len)
+            .collect();
+        b.sort();
+        if batch % 2 == 1 {
+            b.reverse();
+        }
+        v.extend(b.into_iter().map(|x| (x, 0)));
+        boundaries.push(v.len());
+    }
+    // tag the elements with their position to check stability
+    for (i, x) in v.iter_mut().enumerate() {
+        x.1 = i;
+    }
+    boundaries.dedup();
+    (v, boundaries)
+}
+
+#[test]
+pub fn natural_batches() {
+    let pool = adaptive_algorithms::rayon::get_thread_pool();
+    let config = MergesortConfig::new().natural(true);
+    for &count in &[0, 1, 2, 3, 10, 57] {
+        let (mut v, _) = batches(count);
+        let mut expected = v.clone();
+        expected.sort_by_key(|x| x.0);
+        pool.install(|| config.sort_by(&mut v, |a, b| a.0.cmp(&b.0)))
+            .unwrap();
+        assert_eq!(v, expected);
+    }
+    // random data works too
+    let mut v: Vec<u32> = std::iter::repeat_with(rand::random).take(100_000).collect();
+    pool.install(|| config.sort(&mut v)).unwrap();
+    assert!(v.windows(2).all(|w| w[0] <= w[1]));
+}
+
+#[test]
+pub fn natural_sorted_is_linear() {
+    let len = 1_000_000;
+    let comparisons = AtomicUsize::new(0);
+    let config = MergesortConfig::new().natural(true);
+    for v in &mut [
+        (0..len).collect::<Vec<usize>>(),
+        (0..len).rev().collect::<Vec<usize>>(),
+    ] {
+        comparisons.store(0, Ordering::Relaxed);
+        config
+            .sort_by(v, |a, b| {
+                comparisons.fetch_add(1, Ordering::Relaxed);
+                a.cmp(b)
+            })
+            .unwrap();
+        assert!(v.windows(2).all(|w| w[0] <= w[1]));
+        // debug builds check the result with another comparison per element
+        let check = if cfg!(debug_assertions) { len } else { 0 };
+        assert!(comparisons.load(Ordering::Relaxed) < 2 * len + check);
+    }
+}
+
+#[test]
+pub fn explicit_runs() {
+    let pool = adaptive_algorithms::rayon::get_thread_pool();
+    let (mut v, boundaries) = batches(40);
+    // the descending batches get sorted since they're not runs
+    let mut expected = v.clone();
+    expected.sort_by_key(|x| x.0);
+    pool.install(|| mergesort_by_runs(&mut v, &boundaries, |a, b| a.0.cmp(&b.0)));
+    assert_eq!(v, expected);
+
+    let mut v = vec![1, 5, 9, 2, 3, 4, 0, 7];
+    mergesort_runs(&mut v, &[3, 6]);
+    assert_eq!(v, vec![0, 1, 2, 3, 4, 5, 7, 9]);
+}
@@ -0,0 +1,50 @@
+use mergesort::{mergesort, mergesort_by_key};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+#[test]
+pub fn strings() {
+    let mut v: Vec<String> = std::iter::repeat_with(rand::random::<u32>)
+        .take(3usize.pow(9) + 100)
+        .map(|x| x.to_string())
+        .collect();
+    let mut expected = v.clone();
+    expected.sort();
+    let pool = adaptive_algorithms::rayon::get_thread_pool();
+    pool.install(|| mergesort(&mut v));
+    assert_eq!(v, expected);
+}
+
+#[test]
+pub fn boxes() {
+    let mut v: Vec<Box<u64>> = std::iter::repeat_with(rand::random::<u64>)
+        .take(2usize.pow(15))
+        .map(Box::new)
+        .collect();
+    let mut expected = v.clone();
+    expected.sort();
+    let pool = adaptive_algorithms::rayon::get_thread_pool();
+    pool.install(|| mergesort(&mut v));
+    assert_eq!(v, expected);
+}
+
+static DROPS: AtomicUsize = AtomicUsize::new(0);
+struct Counted(Vec<u32>);
+impl Drop for Counted {
+    fn drop(&mut self) {
+        DROPS.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+#[test]
+pub fn every_element_dropped_once() {
+    let len = 3usize.pow(8) + 7;
+    let mut v: Vec<Counted> = (0..len)
+        .map(|_| Counted(vec![rand::random::<u32>() % 100]))
+        .collect();
+    let pool = adaptive_algorithms::rayon::get_thread_pool();
+    pool.install(|| mergesort_by_key(&mut v, |c| c.0[0]));
+    assert_eq!(DROPS.load(Ordering::Relaxed), 0);
+    assert!(v.windows(2).all(|w| w[0].0 <= w[1].0));
+    drop(v);
+    assert_eq!(DROPS.load(Ordering::Relaxed), len);
+}
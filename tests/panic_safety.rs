@@ -0,0 +1,140 @@
+use mergesort::{mergesort_by, MergesortConfig};
+use rand::prelude::*;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// inject a panic after a random number of comparisons and check that no element got lost or
+// duplicated
+#[test]
+pub fn panic_in_comparison() {
+    let pool = adaptive_algorithms::rayon::get_thread_pool();
+    for len in vec![3usize.pow(8), 3usize.pow(8) + 100, 10000] {
+        let original: Vec<String> = (0..len)
+            .map(|_| (thread_rng().gen::<u32>() % 1000).to_string())
+            .collect();
+        let mut expected = original.clone();
+        expected.sort();
+        for _ in 0..20 {
+            let mut v = original.clone();
+            // a sort of `len` elements needs at least `len` comparisons
+            let panic_at = thread_rng().gen_range(0, len * 10);
+            let comparisons = AtomicUsize::new(0);
+            let result = catch_unwind(AssertUnwindSafe(|| {
+                pool.install(|| {
+                    mergesort_by(&mut v, |a, b| {
+                        if comparisons.fetch_add(1, Ordering::Relaxed) == panic_at {
+                            panic!("comparison failed");
+                        }
+                        a.cmp(b)
+                    })
+                })
+            }));
+            if result.is_ok() {
+                assert_eq!(v, expected);
+            }
+            // it's still a permutation of the input
+            v.sort();
+            assert_eq!(v, expected);
+        }
+    }
+}
+
+#[test]
+pub fn panic_with_half_buffer() {
+    // around the last merge
+    panic_with(MergesortConfig::new().half_buffer(true), 15, 17);
+}
+
+// drops[id] counts how often the element `id` got dropped
+struct Tracked<'a> {
+    key: u32,
+    id: usize,
+    drops: &'a [AtomicUsize],
+}
+impl<'a> Drop for Tracked<'a> {
+    fn drop(&mut self) {
+        self.drops[self.id].fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+#[test]
+pub fn panic_with_half_buffer_everywhere() {
+    // small blocks and steps, so there are lots of merges, and no thread pool, so every run
+    // compares the same
+    let config = MergesortConfig::new()
+        .half_buffer(true)
+        .blocksize(4)
+        .merge_work_size(4);
+    let len = 100;
+    // the biggest key is in the left half, so at the end the right half overtakes the left one
+    let keys: Vec<u32> = (0..len)
+        .map(|i| if i == 0 { 20 } else { (i * 7 % 20) as u32 })
+        .collect();
+    let comparisons = AtomicUsize::new(0);
+    let mut panic_at = 0;
+    loop {
+        let drops: Vec<AtomicUsize> = (0..len).map(|_| AtomicUsize::new(0)).collect();
+        let mut v: Vec<Tracked> = (0..len)
+            .map(|id| Tracked {
+                key: keys[id],
+                id,
+                drops: &drops,
+            })
+            .collect();
+        comparisons.store(0, Ordering::Relaxed);
+        let result = catch_unwind(AssertUnwindSafe(|| {
+            config.sort_by(&mut v, |a, b| {
+                if comparisons.fetch_add(1, Ordering::Relaxed) == panic_at {
+                    panic!("comparison failed");
+                }
+                a.key.cmp(&b.key)
+            })
+        }));
+        if result.is_ok() {
+            assert!(v.windows(2).all(|w| w[0].key <= w[1].key));
+        }
+        drop(v);
+        assert!(drops.iter().all(|d| d.load(Ordering::Relaxed) == 1));
+        if result.is_ok() {
+            // it got through without reaching the panic
+            break;
+        }
+        panic_at += 1;
+    }
+}
+
+#[test]
+pub fn panic_in_place() {
+    panic_with(MergesortConfig::new().in_place(true), 0, 15);
+}
+
+// like panic_in_comparison, with the panic after `from * len` to `to * len` comparisons
+fn panic_with(config: MergesortConfig, from: usize, to: usize) {
+    let pool = adaptive_algorithms::rayon::get_thread_pool();
+    let len = 10001;
+    let original: Vec<String> = (0..len)
+        .map(|_| (thread_rng().gen::<u32>() % 1000).to_string())
+        .collect();
+    let mut expected = original.clone();
+    expected.sort();
+    for _ in 0..20 {
+        let mut v = original.clone();
+        let panic_at = thread_rng().gen_range(from * len, to * len);
+        let comparisons = AtomicUsize::new(0);
+        let result = catch_unwind(AssertUnwindSafe(|| {
+            pool.install(|| {
+                config.sort_by(&mut v, |a, b| {
+                    if comparisons.fetch_add(1, Ordering::Relaxed) == panic_at {
+                        panic!("comparison failed");
+                    }
+                    a.cmp(b)
+                })
+            })
+        }));
+        if result.is_ok() {
+            assert_eq!(v, expected);
+        }
+        v.sort();
+        assert_eq!(v, expected);
+    }
+}
@@ -0,0 +1,85 @@
+use mergesort::{par_merge, par_merge_by, par_merge_with_branches};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+fn sorted_vec(len: usize, max: u32) -> Vec<u32> {
+    let mut v: Vec<u32> = std::iter::repeat_with(|| rand::random::<u32>() % max)
+        .take(len)
+        .collect();
+    v.sort();
+    v
+}
+
+#[test]
+pub fn merge_in_pool() {
+    let pool = adaptive_algorithms::rayon::get_thread_pool();
+    for &(l, r) in &[(0, 0), (0, 10), (10, 0), (1, 1), (1000, 3), (100000, 54321)] {
+        let left = sorted_vec(l, u32::MAX);
+        let right = sorted_vec(r, u32::MAX);
+        let mut out = vec![0; l + r];
+        pool.install(|| par_merge(&left, &right, &mut out));
+        // the kernel with branches gets the same result
+        let mut branches = vec![0; l + r];
+        pool.install(|| par_merge_with_branches(&left, &right, &mut branches));
+        assert_eq!(branches, out);
+        let mut expected = [left, right].concat();
+        expected.sort();
+        assert_eq!(out, expected);
+    }
+}
+
+#[test]
+pub fn merge_outside_pool() {
+    let left = sorted_vec(20000, 1000);
+    let right = sorted_vec(30000, 1000);
+    let mut out = vec![0; 50000];
+    par_merge(&left, &right, &mut out);
+    let mut expected = [left, right].concat();
+    expected.sort();
+    assert_eq!(out, expected);
+}
+
+#[test]
+pub fn merge_stable() {
+    // tag every element with its input, ties need to take the left one first
+    let left: Vec<(u32, bool)> = sorted_vec(50000, 100)
+        .into_iter()
+        .map(|x| (x, false))
+        .collect();
+    let right: Vec<(u32, bool)> = sorted_vec(50000, 100)
+        .into_iter()
+        .map(|x| (x, true))
+        .collect();
+    let mut out = vec![(0, false); 100000];
+    let pool = adaptive_algorithms::rayon::get_thread_pool();
+    pool.install(|| par_merge_by(&left, &right, &mut out, |a, b| a.0.cmp(&b.0)));
+    let mut expected = [left, right].concat();
+    expected.sort();
+    assert_eq!(out, expected);
+}
+
+#[test]
+pub fn merge_gallops() {
+    // long stretches from each side, so most of it gets copied in bulk (a plain merge takes a
+    // comparison per element)
+    let left: Vec<u32> = (0..100_000).filter(|x| x / 1000 % 2 == 0).collect();
+    let right: Vec<u32> = (0..100_000).filter(|x| x / 1000 % 2 == 1).collect();
+    let mut out = vec![0; 100_000];
+    let comparisons = AtomicUsize::new(0);
+    par_merge_by(&left, &right, &mut out, |a, b| {
+        comparisons.fetch_add(1, Ordering::Relaxed);
+        a.cmp(b)
+    });
+    assert!(out.iter().copied().eq(0..100_000));
+    assert!(comparisons.load(Ordering::Relaxed) < 30_000);
+    // one after the other
+    let left: Vec<u32> = (0..100_000).collect();
+    let right: Vec<u32> = (100_000..200_000).collect();
+    let mut out = vec![0; 200_000];
+    let comparisons = AtomicUsize::new(0);
+    par_merge_by(&left, &right, &mut out, |a, b| {
+        comparisons.fetch_add(1, Ordering::Relaxed);
+        a.cmp(b)
+    });
+    assert!(out.iter().copied().eq(0..200_000));
+    assert!(comparisons.load(Ordering::Relaxed) <= 1);
+}
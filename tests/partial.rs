@@ -0,0 +1,34 @@
+mod common;
+
+use mergesort::{mergesort_partial, mergesort_partial_by};
+
+#[test]
+pub fn top_k() {
+    let pool = adaptive_algorithms::rayon::get_thread_pool();
+    for &len in &[0, 1, 80, 81, 1000, 3usize.pow(9), 50000] {
+        let v: Vec<String> = std::iter::repeat_with(rand::random::<u32>)
+            .take(len)
+            .map(|x| x.to_string())
+            .collect();
+        let mut expected = v.clone();
+        expected.sort();
+        for &k in &[0, 1, 10, 81, 100, 5000, len] {
+            let k = std::cmp::min(k, len);
+            let mut v = v.clone();
+            pool.install(|| mergesort_partial(&mut v, k));
+            assert_eq!(v[..k], expected[..k]);
+            // the rest is still there
+            v.sort();
+            assert_eq!(v, expected);
+        }
+    }
+}
+
+#[test]
+pub fn partial_stable() {
+    // few distinct keys, so most of the top k are ties
+    let (mut v, expected) = common::tagged(20000, 8);
+    let pool = adaptive_algorithms::rayon::get_thread_pool();
+    pool.install(|| mergesort_partial_by(&mut v, 3000, common::by_key));
+    assert_eq!(v[..3000], expected[..3000]);
+}
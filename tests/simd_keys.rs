@@ -0,0 +1,72 @@
+use mergesort::{mergesort_by, mergesort_keys, par_merge_by, par_merge_keys, Key};
+use std::fmt::Debug;
+
+/// Sorts and merges with the vectorized kernels and with the scalar ones (a closure comparator
+/// never gets them), and checks that the results are the same bit for bit.
+fn check<T: Key + Debug>(v: Vec<T>, bits: fn(&T) -> u64) {
+    let pool = adaptive_algorithms::rayon::get_thread_pool();
+    let mut expected = v.clone();
+    pool.install(|| mergesort_by(&mut expected, |a, b| a.key_cmp(b)));
+    let mut sorted = v.clone();
+    pool.install(|| mergesort_keys(&mut sorted));
+    assert!(sorted.iter().map(bits).eq(expected.iter().map(bits)));
+
+    let (left, right) = expected.split_at(v.len() / 3);
+    let mut right = right.to_vec();
+    right.sort_by(|a, b| a.key_cmp(b));
+    let mut expected = expected.clone();
+    let mut merged = expected.clone();
+    pool.install(|| par_merge_by(left, &right, &mut expected, |a, b| a.key_cmp(b)));
+    pool.install(|| par_merge_keys(left, &right, &mut merged));
+    assert!(merged.iter().map(bits).eq(expected.iter().map(bits)));
+}
+
+fn random_bits(len: usize, few: bool) -> Vec<u64> {
+    // mostly random bits, which are plenty of NaNs as floats, and a few special values
+    let special = [
+        0,
+        1 << 63,
+        1 << 31,
+        !0,
+        1,
+        0x7ff0_0000_0000_0000,
+        0xfff0_0000_0000_0001,
+    ];
+    std::iter::repeat_with(|| match rand::random::<u32>() % 8 {
+        0 => special[rand::random::<usize>() % special.len()],
+        _ if few => rand::random::<u64>() % 16,
+        _ => rand::random(),
+    })
+    .take(len)
+    .collect()
+}
+
+#[test]
+pub fn keys_match_scalar() {
+    for &len in &[0, 1, 7, 100, 10_000, 200_001] {
+        for &few in &[false, true] {
+            let v = random_bits(len, few);
+            check(v.clone(), |&x| x);
+            check(v.iter().map(|&x| x as i64).collect(), |&x| x as u64);
+            check(v.iter().map(|&x| f64::from_bits(x)).collect(), |x| {
+                x.to_bits()
+            });
+            // the 32 bit special values are in the upper half
+            let v: Vec<u32> = v.iter().map(|&x| (x >> 32 | x) as u32).collect();
+            check(v.clone(), |&x| x as u64);
+            check(v.iter().map(|&x| x as i32).collect(), |&x| x as u64);
+            check(v.iter().map(|&x| f32::from_bits(x)).collect(), |x| {
+                x.to_bits() as u64
+            });
+        }
+    }
+}
+
+#[test]
+pub fn float_order() {
+    let mut v = vec![1.0, f64::NAN, -0.0, f64::NEG_INFINITY, 0.0, -f64::NAN, -1.0];
+    mergesort_keys(&mut v);
+    let expected = [-f64::NAN, f64::NEG_INFINITY, -1.0, -0.0, 0.0, 1.0, f64::NAN];
+    let bits = |v: &[f64]| v.iter().map(|x| x.to_bits()).collect::<Vec<_>>();
+    assert_eq!(bits(&v), bits(&expected));
+}
@@ -0,0 +1,102 @@
+use mergesort::mergesort_by;
+
+#[test]
+pub fn reverse() {
+    let mut v: Vec<u64> = std::iter::repeat_with(rand::random)
+        .take(3usize.pow(10) + 17)
+        .collect();
+    let pool = adaptive_algorithms::rayon::get_thread_pool();
+    pool.install(|| mergesort_by(&mut v, |a, b| b.cmp(a)));
+    assert!(v.windows(2).all(|w| w[0] >= w[1]));
+}
+
+#[test]
+pub fn by_derived_ordering() {
+    // sort by the distance to the origin, same as the `Tuple` in the benchmarks, and check that
+    // equal distances stay in input order
+    let mut v: Vec<(usize, usize)> = std::iter::repeat_with(rand::random)
+        .take(2usize.pow(16))
+        .enumerate()
+        .map(|(x, y): (usize, usize)| (y % 100, x))
+        .collect();
+    let dist = |t: &(usize, usize)| t.0 * t.0;
+    let pool = adaptive_algorithms::rayon::get_thread_pool();
+    pool.install(|| mergesort_by(&mut v, |a, b| dist(a).cmp(&dist(b))));
+    assert!(v
+        .windows(2)
+        .all(|w| dist(&w[0]) < dist(&w[1]) || (dist(&w[0]) == dist(&w[1]) && w[0].1 < w[1].1)));
+}
+
+#[derive(Copy, Clone, Debug)]
+struct Record {
+    ts: u32,
+    id: usize,
+}
+
+fn records() -> Vec<Record> {
+    std::iter::repeat_with(rand::random)
+        .take(3usize.pow(9) + 5)
+        .enumerate()
+        .map(|(id, ts): (usize, u32)| Record { ts: ts % 1000, id })
+        .collect()
+}
+
+#[test]
+pub fn by_key() {
+    let mut v = records();
+    let pool = adaptive_algorithms::rayon::get_thread_pool();
+    pool.install(|| mergesort::mergesort_by_key(&mut v, |r| r.ts));
+    assert!(v
+        .windows(2)
+        .all(|w| w[0].ts < w[1].ts || (w[0].ts == w[1].ts && w[0].id < w[1].id)));
+}
+
+#[test]
+pub fn by_cached_key() {
+    let mut v = records();
+    let mut expected = v.clone();
+    let pool = adaptive_algorithms::rayon::get_thread_pool();
+    pool.install(|| mergesort::mergesort_by_cached_key(&mut v, |r| (r.ts % 7, r.ts)));
+    expected.sort_by_cached_key(|r| (r.ts % 7, r.ts));
+    assert!(v.iter().zip(expected.iter()).all(|(a, b)| a.id == b.id));
+}
+
+#[test]
+pub fn try_sort() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    let original: Vec<String> = std::iter::repeat_with(rand::random::<u16>)
+        .take(3usize.pow(9) + 40)
+        .map(|x| x.to_string())
+        .collect();
+    let mut expected = original.clone();
+    expected.sort();
+    let pool = adaptive_algorithms::rayon::get_thread_pool();
+
+    let mut v = original.clone();
+    let result: Result<(), ()> =
+        pool.install(|| mergesort::try_mergesort_by(&mut v, |a, b| Ok(a.cmp(b))));
+    assert_eq!(result, Ok(()));
+    assert_eq!(v, expected);
+
+    for &fail_at in &[0, 100, 10000, 100000] {
+        let mut v = original.clone();
+        let comparisons = AtomicUsize::new(0);
+        let result = pool.install(|| {
+            mergesort::try_mergesort_by(&mut v, |a, b| {
+                let c = comparisons.fetch_add(1, Ordering::Relaxed);
+                if c >= fail_at {
+                    Err(c)
+                } else {
+                    Ok(a.cmp(b))
+                }
+            })
+        });
+        // other threads might still be comparing when the first error happens, but we stop soon
+        match result {
+            Err(c) => assert!(c >= fail_at),
+            Ok(()) => panic!("the error got lost"),
+        }
+        v.sort();
+        assert_eq!(v, expected);
+    }
+}
@@ -0,0 +1,19 @@
+use mergesort::Sorter;
+
+#[test]
+pub fn repeated_sorts() {
+    let mut sorter = Sorter::new();
+    for &len in &[3usize.pow(9), 10, 50000, 0, 3usize.pow(9)] {
+        let mut v: Vec<u64> = std::iter::repeat_with(rand::random).take(len).collect();
+        sorter.sort(&mut v);
+        assert!(v.windows(2).all(|w| w[0] <= w[1]));
+
+        // the same scratch memory works for other types
+        let mut v: Vec<(u8, String)> = std::iter::repeat_with(rand::random::<u8>)
+            .take(len)
+            .map(|x| (x, x.to_string()))
+            .collect();
+        sorter.sort_by(&mut v, |a, b| b.0.cmp(&a.0));
+        assert!(v.windows(2).all(|w| w[0].0 >= w[1].0));
+    }
+}